@@ -0,0 +1,56 @@
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+use tracing::debug;
+use crate::ThrottlerError;
+
+//A script that decides whether anything is streaming, for servers nothing else supports
+#[derive(Clone, Debug)]
+pub struct CommandSource {
+    pub command: String,
+    pub timeout: Duration,
+}
+
+//Run through the shell so pipes and arguments work like they would typed out. Exiting 0 with a number on stdout
+//is that many sessions, exiting 0 with nothing is one, and any other exit code is none
+pub async fn command_get_sessions(source: &CommandSource) -> Result<usize, ThrottlerError> {
+    #[cfg(unix)]
+    let mut command = {
+        let mut command = Command::new("sh");
+        command.arg("-c");
+        command
+    };
+    #[cfg(not(unix))]
+    let mut command = {
+        let mut command = Command::new("cmd");
+        command.arg("/C");
+        command
+    };
+    //Dropped on timeout, which kills it rather than leaving it running
+    let child = command.arg(&source.command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|err| ThrottlerError::InvalidResponse(format!("Failed to run SESSION_COMMAND: {err}")))?;
+    let output = tokio::time::timeout(source.timeout, child.wait_with_output()).await
+        .map_err(|_| ThrottlerError::InvalidResponse(format!("SESSION_COMMAND didn't finish within {} seconds", source.timeout.as_secs())))?
+        .map_err(|err| ThrottlerError::InvalidResponse(format!("Failed to run SESSION_COMMAND: {err}")))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    match output.status.code() {
+        Some(0) => match stdout.trim() {
+            "" => Ok(1),
+            count => count.parse().map_err(|_| ThrottlerError::InvalidResponse(
+                format!("SESSION_COMMAND printed {count}, which isn't a session count. stderr: {}", stderr.trim())
+            )),
+        },
+        Some(code) => {
+            debug!("SESSION_COMMAND exited with {code}, no sessions. stderr: {}", stderr.trim());
+            Ok(0)
+        }
+        None => Err(ThrottlerError::InvalidResponse(format!("SESSION_COMMAND was killed by a signal. stderr: {}", stderr.trim()))),
+    }
+}
@@ -0,0 +1,125 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use reqwest::Client;
+use serde_json::Value;
+use tracing::debug;
+use crate::{redact, Config, During, Operation, ThrottlerError};
+
+//Any endpoint that returns JSON, with the session count picked out by a JSON path
+#[derive(Clone, Debug)]
+pub struct GenericSource {
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub path: JsonPath,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum PathSegment {
+    Key(String),
+    Index(usize),
+    Wildcard,
+}
+
+//A small subset of JSONPath: $ followed by .key, ['key'], [N], [*] or .*
+#[derive(Clone, Debug, PartialEq)]
+pub struct JsonPath {
+    expression: String,
+    segments: Vec<PathSegment>,
+}
+
+impl FromStr for JsonPath {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let expression = value.trim();
+        let Some(mut rest) = expression.strip_prefix('$') else {
+            return Err(format!("{expression} doesn't start with $"));
+        };
+
+        let mut segments = Vec::new();
+        while !rest.is_empty() {
+            if let Some(after) = rest.strip_prefix('.') {
+                let end = after.find(['.', '[']).unwrap_or(after.len());
+                let key = &after[..end];
+                segments.push(match key {
+                    "" => return Err(format!("{expression} has an empty key")),
+                    "*" => PathSegment::Wildcard,
+                    key => PathSegment::Key(key.to_string()),
+                });
+                rest = &after[end..];
+            } else if let Some(after) = rest.strip_prefix('[') {
+                let end = after.find(']').ok_or_else(|| format!("{expression} has an unclosed ["))?;
+                let inner = after[..end].trim();
+                segments.push(if inner == "*" {
+                    PathSegment::Wildcard
+                } else if let Some(key) = inner.strip_prefix('\'').and_then(|key| key.strip_suffix('\''))
+                    .or_else(|| inner.strip_prefix('"').and_then(|key| key.strip_suffix('"'))) {
+                    PathSegment::Key(key.to_string())
+                } else {
+                    PathSegment::Index(inner.parse().map_err(|_| format!("{expression} has an invalid index [{inner}]"))?)
+                });
+                rest = &after[end + 1..];
+            } else {
+                return Err(format!("{expression} has unexpected characters at {rest}"));
+            }
+        }
+
+        Ok(JsonPath { expression: expression.to_string(), segments })
+    }
+}
+
+impl Display for JsonPath {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.expression)
+    }
+}
+
+impl JsonPath {
+    //With a wildcard the count is how many values matched, otherwise the single match has to be
+    //a number, a numeric string or an array, which counts its entries
+    pub fn count(&self, value: &Value) -> Result<usize, String> {
+        let mut matches = vec![value];
+        for segment in &self.segments {
+            matches = matches.into_iter().flat_map(|value| -> Vec<&Value> {
+                match (segment, value) {
+                    (PathSegment::Key(key), Value::Object(object)) => object.get(key).into_iter().collect(),
+                    (PathSegment::Index(index), Value::Array(array)) => array.get(*index).into_iter().collect(),
+                    (PathSegment::Wildcard, Value::Array(array)) => array.iter().collect(),
+                    (PathSegment::Wildcard, Value::Object(object)) => object.values().collect(),
+                    _ => Vec::new(),
+                }
+            }).collect();
+        }
+
+        if self.segments.contains(&PathSegment::Wildcard) {
+            return Ok(matches.into_iter().filter(|value| !value.is_null()).count());
+        }
+
+        match matches.first() {
+            Some(Value::Number(number)) => number.as_u64().map(|count| count as usize)
+                .ok_or_else(|| format!("{} matched {number}, which isn't a count", self.expression)),
+            Some(Value::String(count)) => count.trim().parse()
+                .map_err(|_| format!("{} matched \"{count}\", which isn't a count", self.expression)),
+            Some(Value::Array(array)) => Ok(array.len()),
+            Some(other) => Err(format!("{} matched {other}, which isn't a count", self.expression)),
+            None => Err(format!("{} didn't match anything", self.expression)),
+        }
+    }
+}
+
+pub async fn generic_get_sessions(client: &Client, config: &Config, source: &GenericSource, last_response: &mut Option<String>) -> Result<usize, ThrottlerError> {
+    let mut request = client.get(&source.url);
+    for (name, value) in &source.headers {
+        request = request.header(name, value);
+    }
+    let response = config.retry_policy.send(request, Operation::SessionFetch).await?
+        .error_for_status().during(Operation::SessionFetch)?
+        .json::<Value>().await.during(Operation::SessionFetch)?;
+    debug!("{}", redact(config, &format!("{:?}", response)));
+    if config.debug_endpoint {
+        *last_response = Some(redact(config, &response.to_string()));
+    }
+
+    source.path.count(&response)
+        .map_err(|err| ThrottlerError::InvalidResponse(format!("Unexpected sessions response: {err}")))
+}
@@ -0,0 +1,909 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use chrono::{DateTime, Datelike, NaiveDateTime, Utc};
+use reqwest::{Client, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::{debug, info, warn};
+use crate::{redact, Config, During, Operation, ThrottlerError, SESSION_LOG_TARGET};
+
+//Only the fields we filter on, anything else Jellyfin sends is ignored
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct JellyfinSession {
+    pub user_name: Option<String>,
+    pub device_id: Option<String>,
+    pub device_name: Option<String>,
+    pub client: Option<String>,
+    pub remote_end_point: Option<String>,
+    pub now_playing_item: Option<JellyfinItem>,
+    #[serde(deserialize_with = "null_as_default")]
+    pub play_state: JellyfinPlayState,
+    pub last_playback_check_in: Option<String>,
+    pub last_activity_date: Option<String>,
+    pub transcoding_info: Option<JellyfinTranscodingInfo>,
+    //Names, ids and collection types of the libraries the playing item is in, when resolved
+    #[serde(skip)]
+    pub libraries: Vec<String>,
+}
+
+//Older servers send null rather than leaving the field out
+fn null_as_default<'de, D: serde::Deserializer<'de>, T: Deserialize<'de> + Default>(deserializer: D) -> Result<T, D::Error> {
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct JellyfinItem {
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub parent_id: Option<String>,
+    pub collection_type: Option<String>,
+    #[serde(rename = "Type")]
+    pub item_type: Option<String>,
+    #[serde(deserialize_with = "null_as_default")]
+    pub media_streams: Vec<JellyfinMediaStream>,
+    pub height: Option<u32>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct JellyfinMediaStream {
+    pub bit_rate: Option<u64>,
+    #[serde(rename = "Type")]
+    pub stream_type: Option<String>,
+    pub height: Option<u32>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct JellyfinTranscodingInfo {
+    pub bitrate: Option<u64>,
+    pub height: Option<u32>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct JellyfinPlayState {
+    pub is_paused: bool,
+    pub play_method: Option<String>,
+    //In 100ns ticks from the start of the item
+    pub position_ticks: Option<u64>,
+}
+
+const TICKS_PER_SEC: u64 = 10_000_000;
+
+impl JellyfinSession {
+    fn is_remote(&self) -> bool {
+        //An endpoint we can't parse is most likely a hostname, count it as remote to be safe
+        match self.remote_end_point.as_deref().map(parse_endpoint_ip) {
+            Some(Some(ip)) => !is_local_ip(ip),
+            _ => true
+        }
+    }
+
+    //Transcodes report the bitrate being sent, otherwise add up the streams of what's playing
+    fn bitrate(&self) -> Option<u64> {
+        if let Some(bitrate) = self.transcoding_info.as_ref().and_then(|info| info.bitrate) {
+            return Some(bitrate);
+        }
+
+        let item = self.now_playing_item.as_ref()?;
+        let bitrate: u64 = item.media_streams.iter().filter_map(|stream| stream.bit_rate).sum();
+        (bitrate > 0).then_some(bitrate)
+    }
+
+    //Within the first starting_secs of the item. Clients often report buffering at the start as paused.
+    //Without a position we can't tell, so it counts as starting to err toward throttling
+    fn is_starting(&self, starting_secs: u64) -> bool {
+        starting_secs > 0 && self.now_playing_item.is_some()
+            && self.play_state.position_ticks.is_none_or(|ticks| ticks < starting_secs.saturating_mul(TICKS_PER_SEC))
+    }
+
+    //Same as bitrate, a transcode's output height wins over the source's
+    fn height(&self) -> Option<u32> {
+        if let Some(height) = self.transcoding_info.as_ref().and_then(|info| info.height) {
+            return Some(height);
+        }
+
+        let item = self.now_playing_item.as_ref()?;
+        item.height.or_else(|| item.media_streams.iter()
+            .filter(|stream| stream.stream_type.as_deref() == Some("Video"))
+            .filter_map(|stream| stream.height)
+            .max())
+    }
+}
+
+fn parse_endpoint_ip(endpoint: &str) -> Option<IpAddr> {
+    endpoint.parse::<IpAddr>().ok()
+        .or_else(|| endpoint.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+fn is_local_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        //fc00::/7 is unique local and fe80::/10 link local
+        IpAddr::V6(ip) => ip.is_loopback() || (ip.segments()[0] & 0xfe00) == 0xfc00 || (ip.segments()[0] & 0xffc0) == 0xfe80,
+    }
+}
+
+//A single signal a session can be checked against to decide if it's active
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SessionPredicate {
+    //Every session Jellyfin reports within the active window
+    Any,
+    //Has a NowPlayingItem, whether playing or paused
+    NowPlaying,
+    //Has a NowPlayingItem and isn't paused, or is paused but still starting
+    Playing,
+    Transcoding,
+    //The client isn't on a private, loopback or link local address
+    Remote,
+    //Streaming at least this many bits/s. Playing sessions without bitrate info match, to err toward throttling
+    MinBitrate(u64),
+    //Video at least this many pixels high. Playing sessions without resolution info match, for the same reason
+    MinResolution(u32),
+}
+
+impl SessionPredicate {
+    fn matches(&self, session: &JellyfinSession, starting_secs: u64) -> bool {
+        match self {
+            SessionPredicate::Any => true,
+            SessionPredicate::NowPlaying => session.now_playing_item.is_some(),
+            SessionPredicate::Playing => session.now_playing_item.is_some() && (!session.play_state.is_paused || session.is_starting(starting_secs)),
+            SessionPredicate::Transcoding => session.play_state.play_method.as_deref() == Some("Transcode"),
+            SessionPredicate::Remote => session.is_remote(),
+            //Nothing playing isn't a stream of unknown quality, so it never matches
+            SessionPredicate::MinBitrate(min_bitrate) => session.now_playing_item.is_some() && session.bitrate().is_none_or(|bitrate| bitrate >= *min_bitrate),
+            SessionPredicate::MinResolution(min_height) => session.now_playing_item.is_some() && session.height().is_none_or(|height| height >= *min_height),
+        }
+    }
+}
+
+impl FromStr for SessionPredicate {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "any" => Ok(SessionPredicate::Any),
+            "now_playing" => Ok(SessionPredicate::NowPlaying),
+            "playing" => Ok(SessionPredicate::Playing),
+            "transcoding" => Ok(SessionPredicate::Transcoding),
+            "remote" => Ok(SessionPredicate::Remote),
+            other => Err(format!("unknown session predicate {other}, expected one of any, now_playing, playing, transcoding or remote")),
+        }
+    }
+}
+
+//Decides whether a session that got through the filters counts as active. Every clause has to match and a clause
+//matches if any of its predicates does, the default is just Any so every session Jellyfin reports counts.
+//In JELLYFIN_ACTIVE_POLICY clauses are separated by commas and predicates within one by |, so remote,transcoding
+//only counts remote transcodes while playing,remote|transcoding counts playback that's remote or transcoding
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionActivityPolicy {
+    pub clauses: Vec<Vec<SessionPredicate>>,
+    //Paused sessions this early into their item still count as playing, 0 disables
+    pub starting_secs: u64,
+}
+
+impl Default for SessionActivityPolicy {
+    fn default() -> Self {
+        SessionActivityPolicy { clauses: vec![vec![SessionPredicate::Any]], starting_secs: 0 }
+    }
+}
+
+impl SessionActivityPolicy {
+    pub fn is_active(&self, session: &JellyfinSession) -> bool {
+        self.clauses.iter().all(|clause| clause.iter().any(|predicate| predicate.matches(session, self.starting_secs)))
+    }
+}
+
+impl Display for SessionActivityPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let clauses: Vec<String> = self.clauses.iter().map(|clause| {
+            let predicates: Vec<String> = clause.iter().map(|predicate| format!("{predicate:?}")).collect();
+            if predicates.len() > 1 { format!("({})", predicates.join(" or ")) } else { predicates.join("") }
+        }).collect();
+        write!(f, "{}", clauses.join(" and "))?;
+        if self.starting_secs > 0 && self.clauses.iter().flatten().any(|predicate| *predicate == SessionPredicate::Playing) {
+            write!(f, " (paused in the first {} seconds counts as playing)", self.starting_secs)?;
+        }
+        Ok(())
+    }
+}
+
+//How a user's sessions affect the limit. Ordered so the strictest is the greatest
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum UserPolicy {
+    //Never counted
+    Ignore,
+    //Throttled to the light limit
+    Light,
+    Throttle,
+}
+
+impl FromStr for UserPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "ignore" => Ok(UserPolicy::Ignore),
+            "light" => Ok(UserPolicy::Light),
+            "throttle" => Ok(UserPolicy::Throttle),
+            other => Err(format!("unknown user policy {other}, expected one of throttle, light or ignore")),
+        }
+    }
+}
+
+//Which session timestamp JELLYFIN_SESSION_IDLE_SECS is measured from. The other one is only used when this one is missing
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FreshnessField {
+    //Updated by playback progress reports, so it stops moving while paused
+    LastPlaybackCheckIn,
+    //Updated by any request from the client
+    LastActivityDate,
+}
+
+impl FromStr for FreshnessField {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().replace('_', "").to_lowercase().as_str() {
+            "lastplaybackcheckin" => Ok(FreshnessField::LastPlaybackCheckIn),
+            "lastactivitydate" => Ok(FreshnessField::LastActivityDate),
+            _ => Err(format!("unknown freshness field {}, expected LastPlaybackCheckIn or LastActivityDate", value.trim())),
+        }
+    }
+}
+
+//The sessions that count along with the strictest user policy among them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ActiveSessions {
+    pub count: usize,
+    //Ignore when nothing is active
+    pub strictest: UserPolicy,
+}
+
+#[derive(Clone, Debug)]
+pub struct SessionFilters {
+    pub ignore_devices: Vec<String>,
+    pub ignore_ghosts: bool,
+    //0 leaves freshness to Jellyfin's activeWithinSeconds
+    pub session_idle_secs: u64,
+    pub freshness_field: FreshnessField,
+    //Empty means sessions from any library count
+    pub libraries: Vec<String>,
+    //Empty means sessions from any client count
+    pub clients: Vec<String>,
+    pub policy: SessionActivityPolicy,
+    //Users not listed get Throttle
+    pub user_policies: Vec<(String, UserPolicy)>,
+    pub now: DateTime<Utc>,
+}
+
+impl From<&Config> for SessionFilters {
+    fn from(value: &Config) -> Self {
+        SessionFilters {
+            ignore_devices: value.jellyfin_ignore_devices.clone(),
+            ignore_ghosts: value.jellyfin_ignore_ghost_sessions,
+            session_idle_secs: value.jellyfin_session_idle_secs,
+            freshness_field: value.jellyfin_freshness_field,
+            libraries: value.jellyfin_libraries.clone(),
+            clients: value.jellyfin_clients.clone(),
+            policy: value.jellyfin_activity_policy.clone(),
+            user_policies: value.jellyfin_user_policies.clone(),
+            now: Utc::now()
+        }
+    }
+}
+
+//Library lookups by item id, so each item playing only costs one extra request
+pub type LibraryCache = HashMap<String, Vec<String>>;
+//Stops the cache growing forever on a long running server, it just gets rebuilt
+const MAX_LIBRARY_CACHE_ENTRIES: usize = 1000;
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "PascalCase", default)]
+struct JellyfinAncestor {
+    id: Option<String>,
+    name: Option<String>,
+    #[serde(rename = "Type")]
+    item_type: Option<String>,
+    collection_type: Option<String>,
+}
+
+//Base paths some bundled installs serve the API under, tried after the configured address
+const FALLBACK_BASE_PATHS: [&str; 3] = ["", "/jellyfin", "/emby"];
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "PascalCase", default)]
+struct JellyfinPublicInfo {
+    server_name: Option<String>,
+    version: Option<String>,
+}
+
+//Finds which of the configured address and the common base paths on the same host answers /System/Info/Public
+pub async fn jellyfin_detect_base(client: &Client, config: &Config) -> Option<String> {
+    let configured = config.jellyfin_address.trim_end_matches('/').to_string();
+    let mut candidates = vec![configured.clone()];
+    if let Ok(url) = reqwest::Url::parse(&configured) {
+        let origin = url.origin().ascii_serialization();
+        for path in FALLBACK_BASE_PATHS {
+            let candidate = format!("{origin}{path}");
+            if !candidates.contains(&candidate) {
+                candidates.push(candidate);
+            }
+        }
+    }
+
+    for candidate in candidates {
+        let info = async {
+            client.get(format!("{candidate}/System/Info/Public"))
+                .send()
+                .await?.error_for_status()?.json::<JellyfinPublicInfo>().await
+        }.await;
+        match info {
+            Ok(JellyfinPublicInfo { server_name, version: Some(version) }) => {
+                info!("Found Jellyfin {version} ({}) at {}", server_name.as_deref().unwrap_or("unnamed"), redact(config, &candidate));
+                return Some(candidate);
+            }
+            Ok(_) => debug!("{} answered /System/Info/Public without a version", redact(config, &candidate)),
+            Err(err) => debug!("No Jellyfin at {}: {}", redact(config, &candidate), redact(config, &err.to_string())),
+        }
+    }
+
+    None
+}
+
+//Jellyfin returns every session it knows of when activeWithinSeconds is left off, which is what a window of 0 asks for.
+//Sending 0 instead isn't treated the same way by every Jellyfin version
+fn sessions_url(config: &Config) -> String {
+    match config.jellyfin_query_window_secs() {
+        Some(window_secs) => format!("{}/Sessions?activeWithinSeconds={window_secs}", &config.jellyfin_address),
+        None => format!("{}/Sessions", &config.jellyfin_address),
+    }
+}
+
+pub async fn jellyfin_get_sessions(client: &Client, config: &Config, library_cache: &mut LibraryCache, last_response: &mut Option<String>) -> Result<ActiveSessions, ThrottlerError> {
+    let request = client
+        .get(sessions_url(config))
+        .header("Authorization", format!("MediaBrowser Token={}", &config.jellyfin_api_token));
+    let response = config.retry_policy.send(request, Operation::SessionFetch).await?;
+    check_sessions_access(&response)?;
+    let response = response.json::<Value>().await.during(Operation::SessionFetch)?;
+    debug!("{}", redact(config, &format!("{:?}", response)));
+    if config.debug_endpoint {
+        *last_response = Some(redact(config, &response.to_string()));
+    }
+
+    let sessions = Vec::<JellyfinSession>::deserialize(&response)
+        .map_err(|err| ThrottlerError::InvalidResponse(format!("Unexpected sessions response from Jellyfin: {err}")))?;
+
+    Ok(count_sessions(client, config, sessions, library_cache).await)
+}
+
+//A token without access gets the same answer every time, so it's worth telling apart from Jellyfin being down
+fn check_sessions_access(response: &Response) -> Result<(), ThrottlerError> {
+    let status = response.status();
+    if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+        return Err(ThrottlerError::bad_response(Operation::SessionFetch, response, format!("Jellyfin token lacks access to /Sessions ({status})")));
+    }
+    Ok(())
+}
+
+//Run once at startup. Only a token without access fails it, anything else could still be transient
+pub async fn jellyfin_preflight(client: &Client, config: &Config) -> Result<(), ThrottlerError> {
+    let request = client
+        .get(sessions_url(config))
+        .header("Authorization", format!("MediaBrowser Token={}", &config.jellyfin_api_token));
+    match config.retry_policy.send(request, Operation::SessionFetch).await {
+        Ok(response) => check_sessions_access(&response),
+        Err(err) => {
+            warn!("Couldn't check Jellyfin's /Sessions at startup, carrying on: {err}");
+            Ok(())
+        }
+    }
+}
+
+//Only a rejected token is an error, Jellyfin being unreachable is left to the session fetch to report
+pub async fn jellyfin_check_token(client: &Client, config: &Config) -> Result<(), ThrottlerError> {
+    let request = client
+        .get(format!("{}/System/Info", &config.jellyfin_address))
+        .header("Authorization", format!("MediaBrowser Token={}", &config.jellyfin_api_token));
+    match config.retry_policy.send(request, Operation::TokenCheck).await {
+        Ok(response) if response.status() == StatusCode::UNAUTHORIZED || response.status() == StatusCode::FORBIDDEN => {
+            let status = response.status();
+            Err(ThrottlerError::bad_response(Operation::TokenCheck, &response, format!("Jellyfin rejected the token ({status}), it may have been revoked. Sessions can't be read until JELLYFIN_TOKEN is replaced")))
+        }
+        Ok(response) => {
+            debug!("Jellyfin token check returned {}", response.status());
+            Ok(())
+        }
+        Err(err) => {
+            debug!("Couldn't reach Jellyfin to check the token: {err}");
+            Ok(())
+        }
+    }
+}
+
+//Sessions pushed over the WebSocket aren't limited by activeWithinSeconds, so apply the same window
+//to when they were last active. Sessions without an activity date count, like they would when polling
+pub async fn jellyfin_count_pushed_sessions(client: &Client, config: &Config, mut sessions: Vec<JellyfinSession>, library_cache: &mut LibraryCache) -> ActiveSessions {
+    let now = Utc::now();
+    let Some(window_secs) = config.jellyfin_query_window_secs().map(|window_secs| window_secs as i64) else {
+        return count_sessions(client, config, sessions, library_cache).await;
+    };
+    sessions.retain(|session| {
+        session.last_activity_date.as_deref()
+            .and_then(parse_jellyfin_date)
+            .is_none_or(|last_active| (now - last_active).num_seconds() <= window_secs)
+    });
+
+    count_sessions(client, config, sessions, library_cache).await
+}
+
+async fn count_sessions(client: &Client, config: &Config, mut sessions: Vec<JellyfinSession>, library_cache: &mut LibraryCache) -> ActiveSessions {
+    //Sessions only carry the item's ParentId, which for an episode is its season, so names and collection
+    //types can't match without looking the library up
+    if !config.jellyfin_libraries.is_empty() {
+        resolve_libraries(client, config, &mut sessions, library_cache).await;
+    }
+    if config.log_sessions {
+        log_session_summary(&sessions);
+    }
+
+    parse_active_sessions(&sessions, &SessionFilters::from(config))
+}
+
+//Sessions don't say which library an item is in, so look it up from the item's ancestors
+async fn resolve_libraries(client: &Client, config: &Config, sessions: &mut [JellyfinSession], library_cache: &mut LibraryCache) {
+    if library_cache.len() > MAX_LIBRARY_CACHE_ENTRIES {
+        library_cache.clear();
+    }
+
+    for session in sessions {
+        let Some(item_id) = session.now_playing_item.as_ref().and_then(|item| item.id.clone()) else { continue };
+        if !library_cache.contains_key(&item_id) {
+            match jellyfin_get_libraries(client, config, &item_id).await {
+                Ok(libraries) => {
+                    debug!("Item {item_id} is in libraries {libraries:?}");
+                    library_cache.insert(item_id.clone(), libraries);
+                }
+                //Left uncached so it's retried, the session is treated as an unknown library meanwhile
+                Err(err) => {
+                    warn!("Failed to look up the library of item {item_id}: {err}");
+                    continue;
+                }
+            }
+        }
+        session.libraries = library_cache[&item_id].clone();
+    }
+}
+
+async fn jellyfin_get_libraries(client: &Client, config: &Config, item_id: &str) -> Result<Vec<String>, ThrottlerError> {
+    let request = client
+        .get(format!("{}/Items/{item_id}/Ancestors", &config.jellyfin_address))
+        .header("Authorization", format!("MediaBrowser Token={}", &config.jellyfin_api_token));
+    let ancestors = config.retry_policy.send(request, Operation::LibraryLookup).await?
+        .error_for_status().during(Operation::LibraryLookup)?
+        .json::<Vec<JellyfinAncestor>>().await.during(Operation::LibraryLookup)?;
+
+    Ok(ancestors.into_iter()
+        .filter(|ancestor| ancestor.item_type.as_deref() == Some("CollectionFolder"))
+        .flat_map(|ancestor| [ancestor.name, ancestor.id, ancestor.collection_type])
+        .flatten()
+        .collect())
+}
+
+fn log_session_summary(sessions: &[JellyfinSession]) {
+    info!(target: SESSION_LOG_TARGET, "Jellyfin reported {} sessions", sessions.len());
+    for session in sessions {
+        let state = match &session.now_playing_item {
+            None => "idle",
+            Some(_) if session.play_state.is_paused => "paused",
+            Some(_) => "playing"
+        };
+        info!(
+            target: SESSION_LOG_TARGET,
+            user = session.user_name.as_deref().unwrap_or("-"),
+            device = session.device_name.as_deref().unwrap_or("-"),
+            client = session.client.as_deref().unwrap_or("-"),
+            play_method = session.play_state.play_method.as_deref().unwrap_or("-"),
+            state,
+            media_type = session.now_playing_item.as_ref().and_then(|item| item.item_type.as_deref()).unwrap_or("-"),
+            "Session"
+        );
+    }
+}
+
+//Counts the sessions in a /Sessions response that should trigger throttling. The filters throw out
+//sessions we never care about, then the activity policy decides whether what's left is active.
+//When several users are streaming the strictest of their policies wins
+pub fn parse_active_sessions(sessions: &[JellyfinSession], filters: &SessionFilters) -> ActiveSessions {
+    let active: Vec<UserPolicy> = sessions.iter()
+        .filter(|session| !(filters.ignore_ghosts && is_ghost(session)))
+        .filter(|session| !is_ignored_device(filters, session))
+        .filter(|session| is_allowed_client(filters, session))
+        .filter(|session| is_fresh(filters, session))
+        .filter(|session| is_allowed_library(filters, session))
+        .filter(|session| {
+            let active = filters.policy.is_active(session);
+            if !active {
+                debug!("Session from {} doesn't match the activity policy {}", session.device_name.as_deref().unwrap_or("-"), filters.policy);
+            }
+            active
+        })
+        .map(|session| user_policy(filters, session))
+        .filter(|policy| *policy != UserPolicy::Ignore)
+        .collect();
+
+    ActiveSessions {
+        count: active.len(),
+        strictest: active.into_iter().max().unwrap_or(UserPolicy::Ignore),
+    }
+}
+
+fn user_policy(filters: &SessionFilters, session: &JellyfinSession) -> UserPolicy {
+    let Some(user_name) = &session.user_name else { return UserPolicy::Throttle };
+    let policy = filters.user_policies.iter()
+        .find(|(user, _)| user.eq_ignore_ascii_case(user_name.trim()))
+        .map_or(UserPolicy::Throttle, |(_, policy)| *policy);
+    if policy != UserPolicy::Throttle {
+        debug!("Session from user {user_name} has the {policy:?} policy");
+    }
+    policy
+}
+
+//Some setups keep sessions around with no device behind them that never play anything. Either id or name is
+//enough to count as a real device
+fn is_ghost(session: &JellyfinSession) -> bool {
+    let missing = |field: &Option<String>| field.as_deref().is_none_or(|value| value.trim().is_empty());
+    let ghost = missing(&session.device_id) && missing(&session.device_name);
+    if ghost {
+        debug!("Ignoring ghost session from user {} with no DeviceId or DeviceName", session.user_name.as_deref().unwrap_or("-"));
+    }
+    ghost
+}
+
+fn is_ignored_device(filters: &SessionFilters, session: &JellyfinSession) -> bool {
+    for (field, name) in [("DeviceName", &session.device_name), ("Client", &session.client)] {
+        if let Some(name) = name {
+            if filters.ignore_devices.iter().any(|ignored| ignored.eq_ignore_ascii_case(name.trim())) {
+                debug!("Ignoring session from {field} {name}");
+                return true;
+            }
+        }
+    }
+
+    false
+}
+
+//Sessions that don't say which client they're from can't be on the list
+fn is_allowed_client(filters: &SessionFilters, session: &JellyfinSession) -> bool {
+    if filters.clients.is_empty() {
+        return true;
+    }
+
+    let client = session.client.as_deref().map(str::trim).unwrap_or_default();
+    let allowed = filters.clients.iter().any(|allowed| allowed.eq_ignore_ascii_case(client));
+    if allowed {
+        debug!("Session from client {client} is in JELLYFIN_CLIENTS");
+    } else {
+        debug!("Ignoring session from client {}, it isn't in JELLYFIN_CLIENTS", if client.is_empty() { "-" } else { client });
+    }
+    allowed
+}
+
+fn is_allowed_library(filters: &SessionFilters, session: &JellyfinSession) -> bool {
+    if filters.libraries.is_empty() {
+        return true;
+    }
+
+    let Some(item) = &session.now_playing_item else {
+        return false;
+    };
+
+    let listed = |candidate: &String| filters.libraries.iter().any(|library| library.eq_ignore_ascii_case(candidate));
+    //ParentId is only the library when the item sits straight in it, so it can match but not rule a library out
+    if item.parent_id.as_ref().is_some_and(listed) {
+        return true;
+    }
+    let candidates: Vec<&String> = item.collection_type.iter().chain(&session.libraries).collect();
+    //Better to throttle needlessly than miss a stream from a library we were asked to watch
+    if candidates.is_empty() {
+        debug!("Library of {} is unknown, counting it", item.name.as_deref().unwrap_or("-"));
+        return true;
+    }
+
+    let allowed = candidates.into_iter().any(listed);
+    if !allowed {
+        debug!("Ignoring session playing {} from libraries {:?}", item.name.as_deref().unwrap_or("-"), session.libraries);
+    }
+    allowed
+}
+
+//Decides freshness from when the session last checked in, rather than trusting activeWithinSeconds alone
+fn is_fresh(filters: &SessionFilters, session: &JellyfinSession) -> bool {
+    if filters.session_idle_secs == 0 {
+        return true;
+    }
+
+    let fields = match filters.freshness_field {
+        FreshnessField::LastPlaybackCheckIn => [&session.last_playback_check_in, &session.last_activity_date],
+        FreshnessField::LastActivityDate => [&session.last_activity_date, &session.last_playback_check_in],
+    };
+    let last_seen = fields.into_iter()
+        .filter_map(|date| date.as_deref())
+        .filter_map(parse_jellyfin_date)
+        .next();
+
+    match last_seen {
+        Some(last_seen) => {
+            let idle_secs = (filters.now - last_seen).num_seconds();
+            if idle_secs > filters.session_idle_secs as i64 {
+                debug!("Ignoring session idle for {idle_secs} seconds");
+                false
+            } else {
+                true
+            }
+        }
+        //Jellyfin already filtered by activeWithinSeconds, so without dates that has to be enough
+        None => true
+    }
+}
+
+//Jellyfin reports dates without a value as 0001-01-01, treat those as missing. Some versions leave the
+//offset off, those are UTC
+fn parse_jellyfin_date(date: &str) -> Option<DateTime<Utc>> {
+    let date = date.trim();
+    DateTime::parse_from_rfc3339(date).map(|date| date.with_timezone(&Utc))
+        .or_else(|_| NaiveDateTime::parse_from_str(date, "%Y-%m-%dT%H:%M:%S%.f").map(|date| date.and_utc()))
+        .ok()
+        .filter(|date| date.year() > 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: &str = "2024-05-01T12:00:00Z";
+
+    fn filters() -> SessionFilters {
+        SessionFilters {
+            ignore_devices: Vec::new(),
+            ignore_ghosts: true,
+            session_idle_secs: 0,
+            freshness_field: FreshnessField::LastPlaybackCheckIn,
+            libraries: Vec::new(),
+            clients: Vec::new(),
+            policy: SessionActivityPolicy::default(),
+            user_policies: Vec::new(),
+            now: parse_jellyfin_date(NOW).unwrap(),
+        }
+    }
+
+    //A user playing a movie from the given client
+    fn playing(user: &str, client: &str) -> JellyfinSession {
+        JellyfinSession {
+            user_name: Some(user.to_string()),
+            device_id: Some(format!("{user}-device")),
+            device_name: Some(format!("{user}'s device")),
+            client: Some(client.to_string()),
+            now_playing_item: Some(JellyfinItem {
+                id: Some("item".to_string()),
+                name: Some("Film".to_string()),
+                collection_type: Some("movies".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn paused(user: &str, position_secs: u64) -> JellyfinSession {
+        let mut session = playing(user, "Jellyfin Web");
+        session.play_state.is_paused = true;
+        session.play_state.position_ticks = Some(position_secs * TICKS_PER_SEC);
+        session
+    }
+
+    fn checked_in(user: &str, date: &str) -> JellyfinSession {
+        JellyfinSession { last_playback_check_in: Some(date.to_string()), ..playing(user, "Jellyfin Web") }
+    }
+
+    fn count(sessions: &[JellyfinSession], filters: &SessionFilters) -> usize {
+        parse_active_sessions(sessions, filters).count
+    }
+
+    #[test]
+    fn counts_every_session_by_default() {
+        let sessions = [playing("alice", "Jellyfin Web"), playing("bob", "Infuse")];
+        assert_eq!(parse_active_sessions(&sessions, &filters()), ActiveSessions { count: 2, strictest: UserPolicy::Throttle });
+        assert_eq!(parse_active_sessions(&[], &filters()), ActiveSessions { count: 0, strictest: UserPolicy::Ignore });
+    }
+
+    #[test]
+    fn user_policies() {
+        let filters = SessionFilters {
+            user_policies: vec![("Guest".to_string(), UserPolicy::Ignore), ("kid".to_string(), UserPolicy::Light)],
+            ..filters()
+        };
+        assert_eq!(parse_active_sessions(&[playing("guest", "Infuse")], &filters), ActiveSessions { count: 0, strictest: UserPolicy::Ignore });
+        assert_eq!(parse_active_sessions(&[playing("kid", "Infuse")], &filters), ActiveSessions { count: 1, strictest: UserPolicy::Light });
+        assert_eq!(
+            parse_active_sessions(&[playing("kid", "Infuse"), playing("alice", "Infuse"), playing("guest", "Infuse")], &filters),
+            ActiveSessions { count: 2, strictest: UserPolicy::Throttle }
+        );
+    }
+
+    #[test]
+    fn ignored_devices_match_the_device_name_or_client() {
+        let filters = SessionFilters { ignore_devices: vec!["alice's device".to_string(), "Jellyfin Web".to_string()], ..filters() };
+        let sessions = [playing("alice", "Infuse"), playing("bob", "jellyfin web"), playing("carol", "Infuse")];
+        assert_eq!(count(&sessions, &filters), 1);
+    }
+
+    #[test]
+    fn client_allowlist() {
+        let filters = SessionFilters { clients: vec!["Infuse".to_string(), "Jellyfin Media Player".to_string()], ..filters() };
+        let no_client = JellyfinSession { client: None, ..playing("carol", "") };
+        let sessions = [playing("alice", "infuse"), playing("bob", "Jellyfin Web"), no_client];
+        assert_eq!(count(&sessions, &filters), 1);
+        assert_eq!(count(&sessions, &SessionFilters { clients: Vec::new(), ..filters }), 3);
+    }
+
+    #[test]
+    fn ghost_sessions() {
+        let ghost = JellyfinSession { device_id: None, device_name: Some(" ".to_string()), ..playing("alice", "Infuse") };
+        let named = JellyfinSession { device_id: None, ..playing("bob", "Infuse") };
+        assert_eq!(count(&[ghost.clone(), named.clone()], &filters()), 1);
+        assert_eq!(count(&[ghost, named], &SessionFilters { ignore_ghosts: false, ..filters() }), 2);
+    }
+
+    #[test]
+    fn library_filter() {
+        let filters = SessionFilters { libraries: vec!["Movies".to_string()], ..filters() };
+        let mut show = playing("bob", "Infuse");
+        show.now_playing_item.as_mut().unwrap().collection_type = Some("tvshows".to_string());
+        let mut unknown = playing("carol", "Infuse");
+        unknown.now_playing_item.as_mut().unwrap().collection_type = None;
+        let idle = JellyfinSession { now_playing_item: None, ..playing("dave", "Infuse") };
+        let mut resolved = show.clone();
+        resolved.libraries = vec!["movies".to_string()];
+
+        assert_eq!(count(&[playing("alice", "Infuse")], &filters), 1);
+        assert_eq!(count(&[show], &filters), 0);
+        //Better to throttle than miss a stream from a library we can't place
+        assert_eq!(count(&[unknown], &filters), 1);
+        assert_eq!(count(&[idle], &filters), 0);
+        assert_eq!(count(&[resolved], &filters), 1);
+    }
+
+    #[test]
+    fn quality_thresholds() {
+        let sessions: Vec<JellyfinSession> = serde_json::from_str(include_str!("../tests/fixtures/jellyfin_sessions.json")).unwrap();
+        let browsing = std::slice::from_ref(&sessions[2]);
+        let threshold = |predicate| SessionFilters { policy: SessionActivityPolicy { clauses: vec![vec![predicate]], starting_secs: 0 }, ..filters() };
+
+        //Both playing sessions are 1080p at 6.8-8Mbps, the browsing one plays nothing
+        assert_eq!(count(&sessions, &threshold(SessionPredicate::MinResolution(1080))), 2);
+        assert_eq!(count(&sessions, &threshold(SessionPredicate::MinResolution(2160))), 0);
+        assert_eq!(count(browsing, &threshold(SessionPredicate::MinResolution(1080))), 0);
+        assert_eq!(count(&sessions, &threshold(SessionPredicate::MinBitrate(7000000))), 1);
+        assert_eq!(count(browsing, &threshold(SessionPredicate::MinBitrate(1))), 0);
+
+        //A playing item without the info gets the benefit of the doubt
+        let unknown = playing("alice", "Infuse");
+        assert_eq!(count(std::slice::from_ref(&unknown), &threshold(SessionPredicate::MinResolution(2160))), 1);
+        assert_eq!(count(&[unknown], &threshold(SessionPredicate::MinBitrate(50000000))), 1);
+    }
+
+    #[test]
+    fn library_filter_on_a_sessions_response() {
+        let mut sessions: Vec<JellyfinSession> = serde_json::from_str(include_str!("../tests/fixtures/jellyfin_sessions.json")).unwrap();
+        let filters = SessionFilters { libraries: vec!["4K Movies".to_string()], ..filters() };
+        //Nothing in /Sessions names the library, so until it's resolved both playing sessions count
+        assert_eq!(count(&sessions, &filters), 2);
+
+        sessions[0].libraries = vec!["4K Movies".to_string(), "a0b1c2d3e4f5a6b7c8d9e0f1a2b3c4d5".to_string(), "movies".to_string()];
+        sessions[1].libraries = vec!["Shows".to_string(), "b0c1d2e3f4a5b6c7d8e9f0a1b2c3d4e5".to_string(), "tvshows".to_string()];
+        assert_eq!(count(&sessions, &filters), 1);
+        assert_eq!(count(&sessions, &SessionFilters { libraries: vec!["tvshows".to_string()], ..filters.clone() }), 1);
+        //A library id matches the ParentId of an item straight in that library
+        sessions[0].libraries.clear();
+        assert_eq!(count(&sessions, &SessionFilters { libraries: vec!["f0e1d2c3b4a5968778695a4b3c2d1e0f".to_string()], ..filters }), 1);
+    }
+
+    #[test]
+    fn freshness() {
+        let filters = SessionFilters { session_idle_secs: 60, ..filters() };
+        assert_eq!(count(&[checked_in("alice", "2024-05-01T11:59:30.0000000Z")], &filters), 1);
+        assert_eq!(count(&[checked_in("alice", "2024-05-01T11:58:00.0000000Z")], &filters), 0);
+        //No usable date leaves it to activeWithinSeconds
+        assert_eq!(count(&[checked_in("alice", "0001-01-01T00:00:00.0000000Z")], &filters), 1);
+        assert_eq!(count(&[playing("alice", "Infuse")], &filters), 1);
+        assert_eq!(count(&[checked_in("alice", "2024-05-01T11:58:00.0000000Z")], &SessionFilters { session_idle_secs: 0, ..filters.clone() }), 1);
+
+        let stale_playback = JellyfinSession {
+            last_activity_date: Some("2024-05-01T11:59:50.0000000Z".to_string()),
+            ..checked_in("alice", "2024-05-01T11:50:00.0000000Z")
+        };
+        assert_eq!(count(std::slice::from_ref(&stale_playback), &filters), 0);
+        assert_eq!(count(&[stale_playback], &SessionFilters { freshness_field: FreshnessField::LastActivityDate, ..filters }), 1);
+    }
+
+    #[test]
+    fn paused_sessions() {
+        let policy = |starting_secs| SessionActivityPolicy { clauses: vec![vec![SessionPredicate::Playing]], starting_secs };
+        let filters = SessionFilters { policy: policy(0), ..filters() };
+        assert_eq!(count(&[playing("alice", "Infuse")], &filters), 1);
+        assert_eq!(count(&[paused("alice", 600)], &filters), 0);
+
+        //Paused while still buffering the start of the item
+        let filters = SessionFilters { policy: policy(30), ..filters };
+        assert_eq!(count(&[paused("alice", 5)], &filters), 1);
+        assert_eq!(count(&[paused("alice", 600)], &filters), 0);
+
+        let filters = SessionFilters { policy: SessionActivityPolicy { clauses: vec![vec![SessionPredicate::NowPlaying]], starting_secs: 0 }, ..filters };
+        assert_eq!(count(&[paused("alice", 600)], &filters), 1);
+    }
+
+    #[test]
+    fn deserializes_a_sessions_response() {
+        let sessions: Vec<JellyfinSession> = serde_json::from_str(include_str!("../tests/fixtures/jellyfin_sessions.json")).unwrap();
+        assert_eq!(sessions.len(), 3);
+
+        let transcode = &sessions[0];
+        assert_eq!(transcode.user_name.as_deref(), Some("alice"));
+        assert_eq!(transcode.device_id.as_deref(), Some("QXBwbGVUVjE0LDF8MTcxNDU2MTIzNDU2Nw=="));
+        assert_eq!(transcode.device_name.as_deref(), Some("Apple TV"));
+        assert_eq!(transcode.client.as_deref(), Some("Infuse-Direct"));
+        assert!(transcode.is_remote());
+        assert_eq!(transcode.play_state.play_method.as_deref(), Some("Transcode"));
+        assert_eq!(transcode.play_state.position_ticks, Some(18235670000));
+        assert_eq!(transcode.bitrate(), Some(8000000));
+        assert_eq!(transcode.height(), Some(1080));
+        let item = transcode.now_playing_item.as_ref().unwrap();
+        assert_eq!(item.item_type.as_deref(), Some("Movie"));
+        assert_eq!(item.parent_id.as_deref(), Some("f0e1d2c3b4a5968778695a4b3c2d1e0f"));
+        assert_eq!(item.media_streams.len(), 2);
+
+        let paused = &sessions[1];
+        assert!(paused.play_state.is_paused);
+        assert!(!paused.is_remote());
+        assert_eq!(paused.bitrate(), Some(6884000));
+        assert_eq!(paused.height(), Some(1080));
+
+        let browsing = &sessions[2];
+        assert!(browsing.now_playing_item.is_none());
+        assert_eq!(browsing.last_playback_check_in.as_deref().and_then(parse_jellyfin_date), None);
+
+        let filters = SessionFilters { session_idle_secs: 60, ..filters() };
+        assert_eq!(count(&sessions, &filters), 2);
+        let filters = SessionFilters { policy: SessionActivityPolicy { clauses: vec![vec![SessionPredicate::Playing]], starting_secs: 0 }, ..filters };
+        assert_eq!(count(&sessions, &filters), 1);
+    }
+
+    #[test]
+    fn deserializes_missing_and_null_fields_from_older_servers() {
+        let sessions: Vec<JellyfinSession> = serde_json::from_str(include_str!("../tests/fixtures/jellyfin_sessions_old.json")).unwrap();
+        assert_eq!(sessions.len(), 2);
+
+        let playing = &sessions[0];
+        assert_eq!(playing.user_name, None);
+        assert_eq!(playing.last_playback_check_in, None);
+        assert!(playing.last_activity_date.as_deref().and_then(parse_jellyfin_date).is_some());
+        assert!(playing.is_remote());
+        assert_eq!(playing.bitrate(), None);
+        assert_eq!(playing.height(), None);
+        assert!(playing.now_playing_item.as_ref().unwrap().media_streams.is_empty());
+
+        let empty = &sessions[1];
+        assert!(!empty.play_state.is_paused);
+        assert!(empty.now_playing_item.is_none());
+
+        //The second one has no device at all, so it's a ghost
+        assert_eq!(count(&sessions, &filters()), 1);
+    }
+}
@@ -0,0 +1,146 @@
+use std::time::{Duration, Instant};
+use futures_util::{SinkExt, StreamExt};
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::watch;
+use tokio_tungstenite::{connect_async_tls_with_config, Connector};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, info, warn};
+use crate::jellyfin::JellyfinSession;
+use crate::{redact, Config};
+
+//Jellyfin holds pushes back to at most one per this often
+const SESSIONS_PUSH_INTERVAL_MS: u64 = 1000;
+//Used until Jellyfin says how long it waits for a keep alive with ForceKeepAlive
+const DEFAULT_KEEP_ALIVE_SECS: u64 = 30;
+//Keep alives that can go unanswered before the socket is treated as dead
+const MISSED_KEEP_ALIVES: u32 = 3;
+const RECONNECT_SECS: u64 = 30;
+
+//The latest sessions Jellyfin pushed. None while the socket is down, which is the poll loop's cue to poll instead.
+//Receivers are only notified when what's playing changes, not on every push
+pub type PushedSessions = watch::Receiver<Option<Vec<JellyfinSession>>>;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct SocketMessage {
+    message_type: String,
+    #[serde(default)]
+    data: Value,
+}
+
+//Starts following Jellyfin's session pushes in the background, reconnecting whenever the socket drops
+pub fn jellyfin_subscribe_sessions(config: &Config) -> PushedSessions {
+    let (sender, receiver) = watch::channel(None);
+    tokio::spawn(run(config.clone(), sender));
+    receiver
+}
+
+async fn run(config: Config, sender: watch::Sender<Option<Vec<JellyfinSession>>>) {
+    loop {
+        let err = match listen(&config, &sender).await {
+            Ok(()) => "Jellyfin closed the WebSocket".to_string(),
+            Err(err) => redact(&config, &err),
+        };
+        //Only worth a warning if pushes were being used, otherwise it's just another failed reconnect
+        if sender.send_replace(None).is_some() {
+            warn!("Lost Jellyfin's WebSocket, polling until it reconnects: {err}");
+        } else {
+            debug!("Couldn't use Jellyfin's WebSocket, retrying in {RECONNECT_SECS} seconds: {err}");
+        }
+        tokio::time::sleep(Duration::from_secs(RECONNECT_SECS)).await;
+    }
+}
+
+async fn listen(config: &Config, sender: &watch::Sender<Option<Vec<JellyfinSession>>>) -> Result<(), String> {
+    let mut request = socket_url(&config.jellyfin_address)?.as_str().into_client_request().map_err(|err| err.to_string())?;
+    let authorization = format!("MediaBrowser Token={}", &config.jellyfin_api_token).parse().map_err(|_| "JELLYFIN_TOKEN isn't a valid header value".to_string())?;
+    request.headers_mut().extend(config.extra_headers.clone());
+    request.headers_mut().insert("Authorization", authorization);
+    let connector = config.session_tls.clone().map(Connector::NativeTls);
+    let (socket, _) = connect_async_tls_with_config(request, None, false, connector).await.map_err(|err| format!("Failed to connect: {err}"))?;
+    let (mut write, mut read) = socket.split();
+
+    let start = json!({ "MessageType": "SessionsStart", "Data": format!("0,{SESSIONS_PUSH_INTERVAL_MS}") });
+    write.send(Message::text(start.to_string())).await.map_err(|err| err.to_string())?;
+    info!("Subscribed to session updates over Jellyfin's WebSocket");
+
+    let mut keep_alive_every = Duration::from_secs(DEFAULT_KEEP_ALIVE_SECS);
+    let mut keep_alive = tokio::time::interval(keep_alive_every);
+    let mut last_received = Instant::now();
+    loop {
+        tokio::select! {
+            _ = keep_alive.tick() => {
+                if last_received.elapsed() > keep_alive_every * MISSED_KEEP_ALIVES {
+                    return Err(format!("Nothing received for {} seconds", last_received.elapsed().as_secs()));
+                }
+                let message = json!({ "MessageType": "KeepAlive" });
+                write.send(Message::text(message.to_string())).await.map_err(|err| err.to_string())?;
+            }
+            message = read.next() => {
+                let text = match message {
+                    None | Some(Ok(Message::Close(_))) => return Ok(()),
+                    Some(Err(err)) => return Err(err.to_string()),
+                    Some(Ok(Message::Text(text))) => text,
+                    //Pings are answered by tungstenite itself
+                    Some(Ok(_)) => continue,
+                };
+                last_received = Instant::now();
+
+                let message = serde_json::from_str::<SocketMessage>(&text)
+                    .map_err(|err| format!("Unexpected message from Jellyfin: {err}"))?;
+                match message.message_type.as_str() {
+                    "Sessions" => {
+                        debug!("{}", redact(config, &text));
+                        let sessions = Vec::<JellyfinSession>::deserialize(&message.data)
+                            .map_err(|err| format!("Unexpected sessions pushed by Jellyfin: {err}"))?;
+                        publish(sender, sessions);
+                    }
+                    "ForceKeepAlive" => {
+                        //Data is how long Jellyfin waits before dropping us, so send at twice that rate
+                        let timeout_secs = message.data.as_u64().unwrap_or(DEFAULT_KEEP_ALIVE_SECS * 2);
+                        keep_alive_every = Duration::from_secs((timeout_secs / 2).max(1));
+                        keep_alive = tokio::time::interval(keep_alive_every);
+                    }
+                    other => debug!("Ignoring {other} message from Jellyfin's WebSocket")
+                }
+            }
+        }
+    }
+}
+
+//Always keeps the newest sessions, but only wakes the poll loop when a session starts, stops, pauses or changes item.
+//Progress reports during playback would otherwise wake it about every push
+fn publish(sender: &watch::Sender<Option<Vec<JellyfinSession>>>, sessions: Vec<JellyfinSession>) {
+    sender.send_if_modified(|current| {
+        let changed = current.as_deref().map(playback_fingerprint) != Some(playback_fingerprint(&sessions));
+        *current = Some(sessions);
+        changed
+    });
+}
+
+//Device, playing item, paused and play method of a session
+type PlaybackFingerprint<'a> = (Option<&'a str>, Option<&'a str>, bool, Option<&'a str>);
+
+fn playback_fingerprint(sessions: &[JellyfinSession]) -> Vec<PlaybackFingerprint<'_>> {
+    sessions.iter().map(|session| (
+        session.device_name.as_deref(),
+        session.now_playing_item.as_ref().and_then(|item| item.id.as_deref()),
+        session.play_state.is_paused,
+        session.play_state.play_method.as_deref(),
+    )).collect()
+}
+
+//The socket lives at /socket under the same base path as the API
+fn socket_url(address: &str) -> Result<Url, String> {
+    let mut url = Url::parse(&format!("{}/socket", address.trim_end_matches('/')))
+        .map_err(|err| format!("JELLYFIN_ADDR isn't a valid URL: {err}"))?;
+    let scheme = match url.scheme() {
+        "https" => "wss",
+        _ => "ws"
+    };
+    url.set_scheme(scheme).map_err(|_| format!("Can't open a WebSocket to {}", url.scheme()))?;
+    Ok(url)
+}
@@ -1,23 +1,324 @@
+mod command;
+mod generic;
+mod jellyfin;
+mod jellyfin_socket;
+mod retry;
+mod statsd;
+mod status;
+mod upload_cap;
+
 use std::collections::{hash_map, HashMap};
 use std::fmt::{Display, Formatter};
 use std::process::ExitCode;
-use std::time::Duration;
-use reqwest::{Client, Error, StatusCode};
-use serde::{Serialize};
-use serde_json::Value;
-use tracing::{debug, error, info, Level};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use reqwest::{Client, Error, Response, StatusCode, Url};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, SET_COOKIE};
+use native_tls::{Identity, TlsConnector};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn, Level};
 use std::env;
+use std::path::Path;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Layer;
+use crate::command::{command_get_sessions, CommandSource};
+use crate::generic::{generic_get_sessions, GenericSource};
+use crate::jellyfin::{jellyfin_check_token, jellyfin_count_pushed_sessions, jellyfin_detect_base, jellyfin_get_sessions, jellyfin_preflight, ActiveSessions, FreshnessField, LibraryCache, SessionActivityPolicy, SessionPredicate, UserPolicy};
+use crate::jellyfin_socket::jellyfin_subscribe_sessions;
+use crate::retry::RetryPolicy;
+use crate::statsd::Statsd;
+use crate::status::{render_status_line, RuntimeState, SharedState};
+use crate::upload_cap::UploadCap;
 
 #[derive(Clone, Debug)]
 struct Config {
     qb_address: String,
     qb_username: String,
     qb_password: String,
+    //Empty means log in with the username and password
+    qb_api_key: String,
+    //Sent as the Referer on login instead of qb_address when set
+    qb_referer: String,
     jellyfin_address: String,
     jellyfin_api_token: String,
+    //0 counts every session Jellyfin knows of, however long since it was active
     jellyfin_active_within_secs: u64,
     poll_time_secs: u64,
+    //Empty means the global limit is used instead of per-torrent limits
+    save_path_prefix: String,
+    torrent_refresh_secs: u64,
+    //Leave torrents on private trackers out of per-torrent limits
+    skip_private_torrents: bool,
+    //qBittorrent states that get per-torrent limits, empty limits every state. Left unset it's the seeding states,
+    //plus the downloading ones when there's a download limit. qBittorrent's states are error,
+    //missingFiles, uploading, pausedUP (stoppedUP from 5.0), queuedUP, stalledUP, checkingUP, forcedUP, allocating,
+    //downloading, metaDL, forcedMetaDL, pausedDL (stoppedDL), queuedDL, stalledDL, checkingDL, forcedDL,
+    //checkingResumeData, moving and unknown
+    throttle_torrent_states: Vec<String>,
+    startup_grace_secs: u64,
+    //0 disables clearing the throttle when Jellyfin can't be reached
+    jellyfin_unreachable_unthrottle_secs: u64,
+    throttle_via_preferences: bool,
+    log_unredacted: bool,
+    ip_ban_threshold: u32,
+    ip_ban_backoff_secs: u64,
+    jellyfin_ignore_devices: Vec<String>,
+    //Skip sessions with neither a DeviceId nor a DeviceName, which are leftovers rather than playback
+    jellyfin_ignore_ghost_sessions: bool,
+    pool_idle_timeout_secs: u64,
+    pool_max_idle: usize,
+    auth_retry_secs: u64,
+    //Used for polls and auth retries until the first poll that fully succeeds, 0 uses the normal intervals
+    warmup_poll_secs: u64,
+    //0 leaves the download limit alone
+    throttle_download_limit: u32,
+    throttle_upload_limit: u32,
+    idle_upload_limit: u32,
+    jellyfin_conservative_idle: bool,
+    //Divide the throttled limits between the active sessions
+    throttle_scale_by_sessions: bool,
+    //How much of the previous session count carries into the one used for scaling, 0 is no smoothing
+    throttle_smoothing: f64,
+    qb_min_write_interval_secs: u64,
+    log_sessions: bool,
+    //Empty means the status page is disabled
+    status_bind: String,
+    retry_policy: RetryPolicy,
+    jellyfin_session_idle_secs: u64,
+    jellyfin_freshness_field: FreshnessField,
+    //Session counts over this are capped when scaling the limits
+    jellyfin_max_sessions: usize,
+    //Write the last applied limits again as soon as a new login works, in case qBittorrent restarted
+    reapply_on_login: bool,
+    //How often the Jellyfin token is checked against /System/Info, 0 disables
+    jellyfin_token_check_secs: u64,
+    //Exit when the token check finds it rejected, instead of only logging it
+    jellyfin_token_revoked_exit: bool,
+    //Serve the latest raw responses on the status page's /debug/last
+    debug_endpoint: bool,
+    //How long to wait at startup for qBittorrent and the session source to answer, 0 doesn't wait
+    wait_for_services_secs: u64,
+    //Exit if they haven't answered by then, instead of starting anyway
+    wait_for_services_exit: bool,
+    //Empty disables the pause file check
+    pause_file: String,
+    pause_clears_throttle: bool,
+    jellyfin_libraries: Vec<String>,
+    //Client names whose sessions count, empty counts every client
+    jellyfin_clients: Vec<String>,
+    //0 disables the watchdog, otherwise the loop has poll_time_secs times this long to check in
+    watchdog_multiplier: u64,
+    scheduler_action: SchedulerAction,
+    throttle_force: ThrottleForce,
+    jellyfin_activity_policy: SessionActivityPolicy,
+    jellyfin_user_policies: Vec<(String, UserPolicy)>,
+    light_upload_limit: u32,
+    jellyfin_detect_base: bool,
+    observe_only: bool,
+    session_source: SessionSource,
+    qb_verify_preferences: bool,
+    clear_stale_throttle: bool,
+    qb_address_fallback: String,
+    //Consecutive connection failures before switching to the fallback
+    qb_failover_after: u32,
+    //0 disables the periodic throttle stats
+    stats_interval_secs: u64,
+    verify_after_transition: bool,
+    //Sessions are pushed over Jellyfin's WebSocket while it's connected, with polling as the fallback
+    jellyfin_use_websocket: bool,
+    //Transient failures in a row that are logged as warnings before they're logged as errors
+    error_after_failures: u32,
+    //Upload speed checks that hold off engaging the throttle, empty engages on sessions alone
+    engage_conditions: Vec<EngageCondition>,
+    //Switch forced torrents to normal while throttled so the limits apply to them
+    unforce_torrents: bool,
+    //TLS for qBittorrent and the session source. None when there's no client certificate and certificates are verified
+    qb_tls: Option<TlsConnector>,
+    session_tls: Option<TlsConnector>,
+    //Sent on every request to qBittorrent and the session source, for service tokens on zero trust proxies
+    extra_headers: HeaderMap,
+    //Connection statuses from transfer/info that hold off limit writes, like disconnected or firewalled
+    skip_connection_statuses: Vec<String>,
+    //host:port of a StatsD server to push metrics to, empty disables
+    statsd_address: String,
+    //Bytes that can be uploaded each calendar month, 0 disables the cap
+    monthly_upload_cap: u64,
+    //How much of the cap is used before upload is held to what's left of it
+    upload_cap_start_percent: f64,
+    //Where the month's upload total is kept across restarts, empty keeps it in memory only
+    upload_cap_state_file: String,
+    //Tag added to the torrents being limited while throttled in per-torrent mode, empty disables
+    throttle_tag: String,
+    //Check the Jellyfin token can read /Sessions before starting
+    jellyfin_preflight: bool,
+    //Form field the limit is sent in. qBittorrent itself uses limit, only some forks and proxies differ
+    qb_limit_field: String,
+    //Poll on wall clock multiples of the poll interval instead of an interval after each poll
+    align_polls: bool,
+}
+
+#[derive(Clone, Debug)]
+enum SessionSource {
+    Jellyfin,
+    Generic(GenericSource),
+    Command(CommandSource),
+}
+
+//The limits to apply to qBittorrent in bytes/s, 0 is unlimited. Download is None when it isn't managed,
+//same for the scheduler
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct TransferLimits {
+    upload: u32,
+    download: Option<u32>,
+    scheduler_enabled: Option<bool>,
+}
+
+impl TransferLimits {
+    fn throttled(config: &Config) -> Self {
+        TransferLimits {
+            upload: config.throttle_upload_limit,
+            download: (config.throttle_download_limit > 0).then_some(config.throttle_download_limit),
+            scheduler_enabled: match config.scheduler_action {
+                SchedulerAction::Off => None,
+                SchedulerAction::EnableWhenThrottled => Some(true),
+                SchedulerAction::DisableWhenThrottled => Some(false),
+            }
+        }
+    }
+
+    //The throttled limits with the lighter upload limit, for when only light users are streaming
+    fn light(config: &Config) -> Self {
+        TransferLimits {
+            upload: config.light_upload_limit,
+            ..TransferLimits::throttled(config)
+        }
+    }
+
+    //Splits the throttled limits between sessions, which can be fractional once smoothed
+    fn scaled(self, sessions: f64) -> Self {
+        let scale = |limit: u32| if limit == 0 { 0 } else { ((limit as f64 / sessions.max(1.0)).round() as u32).max(1) };
+        TransferLimits {
+            upload: scale(self.upload),
+            download: self.download.map(scale),
+            ..self
+        }
+    }
+
+    fn idle(config: &Config) -> Self {
+        TransferLimits {
+            upload: config.idle_upload_limit,
+            download: (config.throttle_download_limit > 0).then_some(0),
+            scheduler_enabled: TransferLimits::throttled(config).scheduler_enabled.map(|enabled| !enabled)
+        }
+    }
+}
+
+impl Display for TransferLimits {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "upload limit of {}", self.upload)?;
+        if let Some(download) = self.download {
+            write!(f, " and download limit of {download}")?;
+        }
+        if let Some(enabled) = self.scheduler_enabled {
+            write!(f, " with the scheduler {}", if enabled { "enabled" } else { "disabled" })?;
+        }
+        Ok(())
+    }
+}
+
+//qBittorrent's scheduler switches the alternative speed limits on and off by time of day. While it's
+//enabled it owns alternative speed mode, so anything else toggling alt speed will be overridden at the
+//next scheduled change
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SchedulerAction {
+    Off,
+    EnableWhenThrottled,
+    DisableWhenThrottled,
+}
+
+//Pins the throttle regardless of sessions. There's no time schedule to weigh it against, only the pause
+//file and waiting out a disconnected qBittorrent take precedence
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ThrottleForce {
+    Auto,
+    Always,
+    Never,
+}
+
+//What qBittorrent reports it's actually doing, in bytes/s. Everything is optional since which fields
+//are present depends on the qBittorrent version
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+struct QbTransferInfo {
+    up_info_speed: Option<u64>,
+    //Uploaded since qBittorrent started, in bytes
+    up_info_data: Option<u64>,
+    dl_info_speed: Option<u64>,
+    //0 for unlimited, negative values are read as 0 too
+    #[serde(deserialize_with = "deserialize_limit")]
+    up_rate_limit: Option<u64>,
+    #[serde(deserialize_with = "deserialize_limit")]
+    dl_rate_limit: Option<u64>,
+    connection_status: Option<String>,
+}
+
+//Checks on qBittorrent's upload speed that all have to pass before the throttle engages, each one turned on by its own setting.
+//Once throttled they aren't checked, since the cap itself pulls the speed down
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum EngageCondition {
+    //Uploading faster than the throttled cap, from QB_THROTTLE_SKIP_IF_BELOW_CAP
+    AboveCap,
+    //Uploading at least this many bytes/s, from QB_THROTTLE_MIN_UPLOAD_SPEED
+    AboveFloor(u64),
+}
+
+impl EngageCondition {
+    //Why the throttle shouldn't engage yet, None if this condition is met
+    fn unmet(&self, upload_speed: u64, limits: TransferLimits) -> Option<String> {
+        match self {
+            EngageCondition::AboveCap if limits.upload > 0 && upload_speed < limits.upload as u64 => {
+                Some(format!("already under the cap of {}", limits.upload))
+            }
+            EngageCondition::AboveFloor(floor) if upload_speed < *floor => {
+                Some(format!("under the floor of {floor}"))
+            }
+            _ => None
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct QBTorrent {
+    hash: String,
+    #[serde(default)]
+    save_path: String,
+    //Forced torrents ignore every limit
+    #[serde(default)]
+    force_start: bool,
+    //Only in the torrent list from qBittorrent 5.0, older versions need the properties
+    #[serde(default)]
+    private: Option<bool>,
+    #[serde(default)]
+    state: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct QBTorrentProperties {
+    is_private: Option<bool>,
+}
+
+impl Config {
+    //Sessions we count ourselves as active mustn't be filtered out by Jellyfin first.
+    //None when JELLYFIN_ACTIVE_WITHIN_SECS is 0, which counts every session Jellyfin knows of
+    fn jellyfin_query_window_secs(&self) -> Option<u64> {
+        match self.jellyfin_active_within_secs {
+            0 => None,
+            secs => Some(secs.max(self.jellyfin_session_idle_secs))
+        }
+    }
 }
 
 #[derive(Serialize, Clone, Debug)]
@@ -41,30 +342,142 @@ impl Display for QBCreds {
     }
 }
 
+//How requests to qBittorrent are authenticated. The cookie comes from logging in, an API key is
+//sent as a bearer token for newer qBittorrent versions or proxies that check a header instead
+#[derive(Clone, Debug)]
+enum QbAuth {
+    Cookie(String),
+    ApiKey(String),
+}
+
+impl QbAuth {
+    fn header_name(&self) -> &'static str {
+        match self {
+            QbAuth::Cookie(_) => "Cookie",
+            QbAuth::ApiKey(_) => "Authorization",
+        }
+    }
+
+    fn header_value(&self) -> String {
+        match self {
+            QbAuth::Cookie(cookie) => cookie.clone(),
+            QbAuth::ApiKey(key) => format!("Bearer {key}"),
+        }
+    }
+}
+
+//What a request was for, so a failure says more than the underlying reqwest error
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operation {
+    Auth,
+    SessionFetch,
+    LibraryLookup,
+    TokenCheck,
+    SetLimit,
+    GetLimit,
+    TorrentList,
+    TransferInfo,
+    ForceStart,
+    Tags,
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Operation::Auth => "qBittorrent login",
+            Operation::SessionFetch => "Session fetch",
+            Operation::LibraryLookup => "Jellyfin library lookup",
+            Operation::TokenCheck => "Jellyfin token check",
+            Operation::SetLimit => "qBittorrent set limit",
+            Operation::GetLimit => "qBittorrent get limit",
+            Operation::TorrentList => "qBittorrent torrent list",
+            Operation::TransferInfo => "qBittorrent transfer info",
+            Operation::ForceStart => "qBittorrent set force start",
+            Operation::Tags => "qBittorrent tag",
+        };
+        write!(f, "{name}")
+    }
+}
+
 enum ThrottlerError {
-    ReqwestError(String),
-    BadResponse(String, StatusCode),
+    //The URL has any password redacted, and is None when reqwest failed before it had one
+    ReqwestError { operation: Operation, url: Option<String>, message: String },
+    //Answered, but not with what was wanted. The URL has any password redacted
+    BadResponse { operation: Operation, url: String, message: String, status: StatusCode },
+    InvalidResponse(String),
+    CredentialsRejected,
     NoCookie,
+    //Login answered Ok. but the cookie didn't make it to us
+    CookieStripped,
 }
 
 impl Display for ThrottlerError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let display_str = match self {
-            ThrottlerError::ReqwestError(message) => {message.as_str()}
-            ThrottlerError::BadResponse(message, _status) => {message.as_str()}
+            ThrottlerError::ReqwestError { operation, url: Some(url), message } => {
+                return write!(f, "{operation} request to {url} failed: {message}");
+            }
+            ThrottlerError::ReqwestError { operation, url: None, message } => {
+                return write!(f, "{operation} request failed: {message}");
+            }
+            ThrottlerError::BadResponse { operation, url, message, .. } => {
+                return write!(f, "{operation} request to {url} failed: {message}");
+            }
+            ThrottlerError::InvalidResponse(message) => {message.as_str()}
+            ThrottlerError::CredentialsRejected => {"qBittorrent rejected the username or password"}
             ThrottlerError::NoCookie => {"No Cookie Returned"}
+            ThrottlerError::CookieStripped => {"qBittorrent login succeeded but no SID cookie was received, check reverse proxy cookie handling"}
         };
 
         write!(f, "{}", display_str)
     }
 }
 
-impl From<Error> for ThrottlerError {
-    fn from(value: Error) -> Self {
-        ThrottlerError::ReqwestError(format!("Error calling QBittorrent. Status: {}", value))
+impl ThrottlerError {
+    fn bad_response(operation: Operation, response: &Response, message: String) -> Self {
+        ThrottlerError::BadResponse { operation, url: redact_url(response.url().as_str()), message, status: response.status() }
+    }
+}
+
+//Turns a reqwest failure into a ThrottlerError saying which operation it happened during
+trait During<T> {
+    fn during(self, operation: Operation) -> Result<T, ThrottlerError>;
+}
+
+impl<T> During<T> for Result<T, Error> {
+    fn during(self, operation: Operation) -> Result<T, ThrottlerError> {
+        self.map_err(|err| ThrottlerError::ReqwestError {
+            operation,
+            url: err.url().map(|url| redact_url(url.as_str())),
+            message: err.without_url().to_string(),
+        })
+    }
+}
+
+//Removes --config-json and its value from the arguments, taking either --config-json <json> or --config-json=<json>
+fn take_config_json_arg(args: &mut Vec<String>) -> Option<String> {
+    let position = args.iter().position(|arg| arg == "--config-json" || arg.starts_with("--config-json="))?;
+    let arg = args.remove(position);
+    match arg.strip_prefix("--config-json=") {
+        Some(json) => Some(json.to_string()),
+        None if position < args.len() => Some(args.remove(position)),
+        None => Some(String::new()),
     }
 }
 
+//A JSON object of env variable names to values, as one string for launchers that would rather not set each variable.
+//Numbers and booleans are taken as their text, anything else has to be a string
+fn parse_config_json(json: &str) -> Result<Vec<(String, String)>, String> {
+    let object: serde_json::Map<String, serde_json::Value> = serde_json::from_str(json)
+        .map_err(|err| format!("Config JSON is invalid: {err}"))?;
+    object.into_iter().map(|(key, value)| match value {
+        serde_json::Value::String(value) => Ok((key, value)),
+        serde_json::Value::Number(value) => Ok((key, value.to_string())),
+        serde_json::Value::Bool(value) => Ok((key, value.to_string())),
+        other => Err(format!("Config JSON has {other} for {key}, expected a string, number or boolean")),
+    }).collect()
+}
+
 fn apply_env<I>(current_env: &mut HashMap<String, Option<String>>, load_env: I) where I: Iterator<Item=(String, String)> {
     for env_var in load_env {
         if let hash_map::Entry::Occupied(mut e) = current_env.entry(env_var.0) {
@@ -73,206 +486,2365 @@ fn apply_env<I>(current_env: &mut HashMap<String, Option<String>>, load_env: I)
     }
 }
 
+//Replaces ${VAR} references in a config value with the value of VAR
+fn expand_vars(value: &str, lookup: &HashMap<String, String>) -> Result<String, String> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('}') else {
+            return Err(format!("unterminated reference in \"{value}\""));
+        };
+        let name = &rest[start + 2..start + end];
+        match lookup.get(name) {
+            Some(var_value) => expanded.push_str(var_value),
+            None => return Err(format!("${{{name}}} references an undefined variable")),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+const SESSION_LOG_TARGET: &str = "qBitThrottler::sessions";
 const DEFAULT_POLL_TIME_SECS: u64 = 5;
 const DEFAULT_JELLYFIN_ACTIVE_WITHIN_SECS: u64 = 5;
+const DEFAULT_TORRENT_REFRESH_SECS: u64 = 300;
+const DEFAULT_THROTTLE_UPLOAD_LIMIT: u32 = 1000;
+const DEFAULT_LIGHT_UPLOAD_LIMIT: u32 = 5000;
+//How many clean zero session polls in a row JELLYFIN_CONSERVATIVE_IDLE needs before unthrottling
+const CONSERVATIVE_IDLE_POLLS: u32 = 2;
+const DEFAULT_IP_BAN_THRESHOLD: u32 = 3;
+const DEFAULT_IP_BAN_BACKOFF_SECS: u64 = 300;
+const DEFAULT_POOL_IDLE_TIMEOUT_SECS: u64 = 90;
+const DEFAULT_POOL_MAX_IDLE: u64 = 2;
+const TCP_KEEPALIVE_SECS: u64 = 60;
+const DEFAULT_RETRY_MAX: u32 = 2;
+const DEFAULT_RETRY_BASE_MS: u64 = 500;
+const DEFAULT_RETRY_MULTIPLIER: f64 = 2.0;
+const MAX_RETRY_MULTIPLIER: f64 = 10.0;
+const DEFAULT_QB_FAILOVER_AFTER: u32 = 3;
+const SELF_TEST_UPLOAD_LIMIT: u32 = 1234 * 1024;
+const DEFAULT_JELLYFIN_MAX_SESSIONS: u64 = 100;
+const DEFAULT_JELLYFIN_TOKEN_CHECK_SECS: u64 = 600;
+const WAIT_FOR_SERVICES_RETRY_SECS: u64 = 5;
+const FAILBACK_PROBE_TIMEOUT_SECS: u64 = 2;
+const MIN_REDACTED_SECRET_LEN: usize = 8;
+const DEFAULT_SESSION_COMMAND_TIMEOUT_SECS: u64 = 10;
+//The states where a torrent is seeding
+const DEFAULT_THROTTLE_TORRENT_STATES: &str = "uploading,stalledUP,forcedUP";
+//Added to the default states when there's a download limit, which would never apply otherwise
+const DOWNLOADING_TORRENT_STATES: &str = "downloading,stalledDL,forcedDL,metaDL,forcedMetaDL";
+//Caps the IP ban backoff at 2^4 times the base
+const MAX_IP_BAN_BACKOFF_DOUBLINGS: u32 = 4;
 
 #[tokio::main]
 async fn main() -> ExitCode {
-    let collector = tracing_subscriber::fmt()
-        .with_max_level(get_log_level())
-        .finish();
+    //Session summaries are only logged when asked for, so they get through regardless of the log level
+    let filter = Targets::new()
+        .with_default(get_log_level())
+        .with_target(SESSION_LOG_TARGET, Level::INFO);
+    let collector = tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(filter));
     tracing::subscriber::set_global_default(collector).unwrap();
 
-    let config = match load_config() {
-        Ok(config) => {config}
-        Err(err) => {return err}
+    //--config-json can come before or after the command, what's left is the command
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let config_json = take_config_json_arg(&mut args);
+
+    //--validate only checks the config, without making any requests
+    let mut problems = ConfigProblems::default();
+    let config = load_config(&mut problems, config_json);
+    if args.first().map(String::as_str) == Some("--validate") {
+        return problems.report();
+    }
+    let mut config = match config {
+        Some(config) => {config}
+        None => {return 1.into()}
+    };
+
+    let (client, session_client) = match (build_client(&config, &config.qb_tls), build_client(&config, &config.session_tls)) {
+        (Ok(client), Ok(session_client)) => (client, session_client),
+        (Err(err), _) | (_, Err(err)) => {
+            error!("Failed to create HTTP client: {err}");
+            return 1.into();
+        }
+    };
+    match args.first().map(String::as_str) {
+        None => {}
+        Some("limits") => return print_limits(&client, &config).await,
+        Some("selftest") => return self_test(&client, &config).await,
+        Some(other) => {
+            error!("Unknown command {other}, expected limits, selftest, --validate or no command to run the throttler");
+            return 2.into();
+        }
+    }
+
+    info!(
+        poll_time_secs = config.poll_time_secs,
+        jellyfin_active_within_secs = config.jellyfin_active_within_secs,
+        throttle_upload_limit = config.throttle_upload_limit,
+        idle_upload_limit = config.idle_upload_limit,
+        activity_policy = %config.jellyfin_activity_policy,
+        media_server = match config.session_source { SessionSource::Jellyfin => "jellyfin", SessionSource::Generic(_) => "generic", SessionSource::Command(_) => "command" },
+        qb_address = %redact_url(&config.qb_address),
+        "Starting up"
+    );
+    if config.log_unredacted {
+        warn!("QB_THROTTLER_LOG_UNREDACTED is set, debug logs will contain session cookies and tokens");
+    }
+    if config.wait_for_services_secs > 0 && !wait_for_services(&client, &session_client, &config).await && config.wait_for_services_exit {
+        return 1.into();
+    }
+    if config.jellyfin_detect_base && matches!(config.session_source, SessionSource::Jellyfin) {
+        match jellyfin_detect_base(&session_client, &config).await {
+            Some(address) => {
+                if address != config.jellyfin_address.trim_end_matches('/') {
+                    info!("Using detected Jellyfin address {} instead of JELLYFIN_ADDR", redact_url(&address));
+                }
+                config.jellyfin_address = address;
+            }
+            None => warn!("Couldn't find Jellyfin at JELLYFIN_ADDR or any common base path, using JELLYFIN_ADDR as is")
+        }
+    }
+    if config.jellyfin_preflight && matches!(config.session_source, SessionSource::Jellyfin) {
+        if let Err(err) = jellyfin_preflight(&session_client, &config).await {
+            error!("{err}");
+            return 1.into();
+        }
+    }
+    let runtime_state: SharedState = Arc::new(Mutex::new(RuntimeState::new()));
+    if !config.status_bind.is_empty() {
+        match TcpListener::bind(&config.status_bind).await {
+            Ok(listener) => {
+                info!("Status page listening on http://{}", config.status_bind);
+                if config.debug_endpoint {
+                    warn!("QB_THROTTLER_DEBUG_ENDPOINT is set, /debug/last shows the latest session and qBittorrent responses to anyone who can reach the status page");
+                }
+                //Polls can be as far apart as the warmup interval before the first success
+                let health_interval = Duration::from_secs(config.poll_time_secs.max(config.warmup_poll_secs).max(1));
+                tokio::spawn(status::serve(listener, runtime_state.clone(), health_interval, config.debug_endpoint));
+            }
+            Err(err) => {
+                error!("Failed to bind status page to {}: {err}", config.status_bind);
+                return 1.into();
+            }
+        }
+    }
+
+    let statsd = if config.statsd_address.is_empty() {
+        None
+    } else {
+        match Statsd::connect(&config.statsd_address).await {
+            Ok(statsd) => {
+                info!("Sending metrics to StatsD at {}", config.statsd_address);
+                Some(statsd)
+            }
+            Err(err) => {
+                error!("Failed to set up StatsD at {}: {err}", config.statsd_address);
+                return 1.into();
+            }
+        }
+    };
+
+    tokio::spawn(print_status_on_sigusr1(runtime_state.clone()));
+    if config.observe_only {
+        warn!("QB_THROTTLER_OBSERVE_ONLY is set, qBittorrent is only read from and a summary is printed on exit");
+        tokio::spawn(observe_summary_on_shutdown(runtime_state.clone()));
+    }
+
+    match config.throttle_force {
+        ThrottleForce::Auto => {}
+        ThrottleForce::Always => warn!("QB_THROTTLE_FORCE is always, the throttled limits stay applied and sessions aren't polled"),
+        ThrottleForce::Never => warn!("QB_THROTTLE_FORCE is never, the idle limits stay applied and sessions aren't polled"),
+    }
+
+    let unforced_torrents: UnforcedTorrents = Arc::new(Mutex::new(None));
+    if config.unforce_torrents && !config.observe_only {
+        tokio::spawn(restore_forced_on_shutdown(client.clone(), unforced_torrents.clone()));
+    }
+
+    let watchdog = (config.watchdog_multiplier > 0).then(|| {
+        let watchdog = Watchdog::new(Duration::from_secs(config.poll_time_secs.max(1) * config.watchdog_multiplier));
+        tokio::spawn(watchdog.clone().watch());
+        watchdog
+    });
+    let feed_watchdog = |allowance: Duration| {
+        if let Some(watchdog) = &watchdog {
+            watchdog.feed(allowance);
+        }
     };
 
-    info!("Starting up");
-    let client = Client::new();
+    //Hashes of torrents matching the save path prefix and when they were last fetched
+    let mut torrent_hashes: Option<(Instant, Vec<String>)> = None;
+    //Whether each torrent is private, kept for as long as the torrent exists since it can't change
+    let mut torrent_privacy: HashMap<String, bool> = HashMap::new();
+    //Torrents carrying QB_THROTTLE_TAG, until it's been removed from them
+    let mut tagged_torrents: Vec<String> = Vec::new();
+    let startup_at = Instant::now();
+    let mut in_startup_grace = config.startup_grace_secs > 0;
+    //Polls come every QB_THROTTLER_WARMUP_POLL_SECS instead until one gets through completely
+    let mut warming_up = config.warmup_poll_secs > 0;
+    let mut jellyfin_unreachable_since: Option<Instant> = None;
+    let mut unreachable_unthrottled = false;
+    let mut post_auth_forbidden: u32 = 0;
+    let mut was_paused = false;
+    //The QB_THROTTLE_SKIP_CONNECTION_STATUS status qBittorrent was last seen in
+    let mut skipped_connection_status: Option<String> = None;
+    let mut clean_zero_polls: u32 = 0;
+    let mut smoothed_sessions: Option<f64> = None;
+    let mut over_max_sessions = false;
+    //The preflight covers startup, so the first check waits a full interval
+    let mut last_token_check = Instant::now();
+    let mut last_write: Option<Instant> = None;
+    let mut deferred_limits: Option<PendingWrite> = None;
+    let mut stale_throttle_checked = false;
+    let mut observe_auth_failed = false;
+    let mut qb_failover = QbFailover::new(&config);
+    let mut session_failures = FailureStreak::default();
+    let mut write_failures = FailureStreak::default();
+    //Start of the current stats window, with the throttled time and transition count at that point
+    let mut stats_window = (Instant::now(), Duration::ZERO, 0);
+    //Limits written by the last transition along with the ones before them, checked on the next poll
+    let mut pending_verification: Option<(TransferLimits, Option<TransferLimits>)> = None;
+    let mut library_cache = LibraryCache::new();
+    let mut upload_cap = (config.monthly_upload_cap > 0).then(|| {
+        if config.upload_cap_state_file.is_empty() {
+            warn!("QB_UPLOAD_CAP_STATE_FILE isn't set, the month's upload total starts again from 0 on restart");
+        }
+        UploadCap::load(config.monthly_upload_cap, config.upload_cap_start_percent, &config.upload_cap_state_file)
+    });
+    //QB_THROTTLE_FORCE decides the limits on its own, so there's nothing to fetch sessions for
+    let sessions_pinned = config.throttle_force != ThrottleForce::Auto;
+    let mut pushed_sessions = match (&config.session_source, config.jellyfin_use_websocket) {
+        _ if sessions_pinned => None,
+        (SessionSource::Jellyfin, true) => Some(jellyfin_subscribe_sessions(&config)),
+        (_, true) => {
+            warn!("JELLYFIN_USE_WEBSOCKET only applies to the jellyfin session source, polling instead");
+            None
+        }
+        (_, false) => None
+    };
 
     loop {
-        let cookie_req = qb_auth(&client, &config).await;
+        if post_auth_forbidden >= config.ip_ban_threshold {
+            //Logging in again straight away would only count as more failures against us, so wait it out
+            let exponent = (post_auth_forbidden - config.ip_ban_threshold).min(MAX_IP_BAN_BACKOFF_DOUBLINGS);
+            let backoff_secs = config.ip_ban_backoff_secs.saturating_mul(1 << exponent);
+            warn!("qBittorrent rejected {post_auth_forbidden} requests in a row straight after logging in, possible IP ban. Backing off for {backoff_secs} seconds");
+            feed_watchdog(Duration::from_secs(backoff_secs));
+            tokio::time::sleep(Duration::from_secs(backoff_secs)).await;
+        }
+
+        feed_watchdog(Duration::ZERO);
+        let auth_req = if config.qb_api_key.is_empty() {
+            qb_auth(&client, &config).await.map(QbAuth::Cookie)
+        } else {
+            Ok(QbAuth::ApiKey(config.qb_api_key.clone()))
+        };
 
-        let cookie = match cookie_req {
-            Ok(cookie) => { cookie }
+        let auth = match auth_req {
+            Ok(auth) => {
+                observe_auth_failed = false;
+                Some(auth)
+            }
+            //Observing only reads from qBittorrent, so sessions are still watched while it can't be logged in to
+            Err(err) if config.observe_only => {
+                if !observe_auth_failed {
+                    warn!("Failed to log in to qBittorrent, observing without its transfer info until it works: {err}");
+                    observe_auth_failed = true;
+                }
+                None
+            }
             Err(err) => {
+                let retry_secs = if warming_up { config.warmup_poll_secs } else { config.auth_retry_secs };
                 match err {
-                    ThrottlerError::BadResponse(_, code) => {
+                    ThrottlerError::BadResponse { status: code, .. } => {
                         if code == StatusCode::UNAUTHORIZED || code == StatusCode::FORBIDDEN {
                             error!("qBittorrent Auth failed critically. Check credentials");
                             break;
                         }
                     }
+                    ThrottlerError::CredentialsRejected => {
+                        error!("{err}. Check credentials");
+                        break;
+                    }
                     ThrottlerError::NoCookie => {
                         error!("qBittorrent Auth failed critically. Check credentials");
                         break;
                     },
+                    ThrottlerError::CookieStripped => {
+                        error!("{err}");
+                        break;
+                    }
+                    ThrottlerError::ReqwestError { .. } if qb_failover.record_failure(&mut config) => {
+                        continue;
+                    }
                     _ => {
-                        info!("Auth failure not critical, retrying in {} seconds", config.poll_time_secs)
+                        info!("Auth failure not critical, retrying in {retry_secs} seconds")
                     }
                 }
 
                 //Any errors that aren't auth related should be solved by waiting
+                feed_watchdog(Duration::from_secs(retry_secs));
+                tokio::time::sleep(Duration::from_secs(retry_secs)).await;
                 continue;
             }
         };
-        debug!("{}", cookie);
+        if let Some(QbAuth::Cookie(cookie)) = &auth {
+            debug!("{}", redact(&config, cookie));
+            qb_failover.record_success();
+        }
 
+        //Writes only go out with this, which observing never has
+        let write_auth = auth.as_ref().filter(|_| !config.observe_only);
+        let mut fresh_login = true;
         loop {
-            let sessions_req = jellyfin_get_sessions(&client, &config).await;
-            let sessions = match sessions_req {
-                Ok(sessions) => { sessions }
-                Err(err) => {
+            //Set when observing needs to log in again before reading from qBittorrent
+            let mut relogin = auth.is_none();
+            let poll_started = Instant::now();
+            feed_watchdog(Duration::ZERO);
+            if qb_failover.primary_recovered(&client).await {
+                qb_failover.fail_back(&mut config);
+                break;
+            }
+            //Checked before this poll writes again, so anything else touching the limit has had a poll to show up
+            if let (Some((expected, previous)), Some(auth)) = (pending_verification.take(), write_auth) {
+                verify_transition(&client, &config, auth, expected, previous).await;
+            }
+            //A failed session fetch looks the same whether Jellyfin is down or the token was revoked, so the token gets checked on its own
+            if config.jellyfin_token_check_secs > 0 && matches!(config.session_source, SessionSource::Jellyfin) && !sessions_pinned
+                && last_token_check.elapsed() >= Duration::from_secs(config.jellyfin_token_check_secs) {
+                last_token_check = Instant::now();
+                if let Err(err) = jellyfin_check_token(&session_client, &config).await {
                     error!("{err}");
-                    return 1.into();
+                    if config.jellyfin_token_revoked_exit {
+                        return 1.into();
+                    }
+                }
+            }
+            //Raw responses for /debug/last, only filled in when it's turned on
+            let mut last_sessions_response = None;
+            let mut last_transfer_info_response = None;
+            let sessions_req = match &config.session_source {
+                _ if sessions_pinned => None,
+                SessionSource::Jellyfin => Some(match pushed_sessions.as_mut().and_then(|pushed| pushed.borrow_and_update().clone()) {
+                    Some(sessions) => Ok(jellyfin_count_pushed_sessions(&session_client, &config, sessions, &mut library_cache).await),
+                    None => jellyfin_get_sessions(&session_client, &config, &mut library_cache, &mut last_sessions_response).await,
+                }),
+                //Nothing to apply user policies to, every session counts as a throttle user
+                SessionSource::Generic(source) => Some(generic_get_sessions(&session_client, &config, source, &mut last_sessions_response).await
+                    .map(|count| ActiveSessions { count, strictest: if count > 0 { UserPolicy::Throttle } else { UserPolicy::Ignore } })),
+                SessionSource::Command(source) => Some(command_get_sessions(source).await
+                    .map(|count| ActiveSessions { count, strictest: if count > 0 { UserPolicy::Throttle } else { UserPolicy::Ignore } })),
+            };
+            //None means we couldn't tell, in which case the current limit is left alone
+            let mut light_only = false;
+            //Why the limits were picked, for the transition log and the status page
+            let mut reason = String::new();
+            let mut fetched_sessions = None;
+            let sessions = match sessions_req {
+                Some(Ok(ActiveSessions { count: sessions, strictest })) => {
+                    session_failures.record_success();
+                    light_only = strictest == UserPolicy::Light;
+                    fetched_sessions = Some(sessions);
+                    if jellyfin_unreachable_since.take().is_some() {
+                        info!("Jellyfin is reachable again");
+                        unreachable_unthrottled = false;
+                    }
+                    clean_zero_polls = if sessions == 0 { clean_zero_polls.saturating_add(1) } else { 0 };
+                    if config.jellyfin_conservative_idle && sessions == 0 && clean_zero_polls < CONSERVATIVE_IDLE_POLLS {
+                        debug!("No sessions but only {clean_zero_polls} clean idle poll(s) in a row, holding the current limit");
+                        None
+                    } else {
+                        Some(sessions)
+                    }
+                }
+                Some(Err(err)) => {
+                    session_failures.record_failure(&config, &err.to_string());
+                    clean_zero_polls = 0;
+                    let unreachable_since = *jellyfin_unreachable_since.get_or_insert_with(Instant::now);
+                    if config.jellyfin_unreachable_unthrottle_secs > 0
+                        && unreachable_since.elapsed() >= Duration::from_secs(config.jellyfin_unreachable_unthrottle_secs) {
+                        if !unreachable_unthrottled {
+                            warn!("Jellyfin has been unreachable for over {} seconds, clearing throttle", config.jellyfin_unreachable_unthrottle_secs);
+                            unreachable_unthrottled = true;
+                        }
+                        reason = format!("Jellyfin unreachable for over {} seconds", config.jellyfin_unreachable_unthrottle_secs);
+                        Some(0)
+                    } else {
+                        None
+                    }
                 }
+                None => None
             };
 
-            let speed = if sessions > 0 {
-                debug!("Session is active, throttling");
-                1000
-            } else {
-                debug!("Session is not active, removing throttling");
-                0
-            };
-            if let Err(ThrottlerError::BadResponse(_, status)) = qb_set_upload(&client, &config, &cookie, speed).await {
-                //Exit the loop to re-auth if auth fails
-                if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
-                    break;
+            let limits = match sessions {
+                Some(sessions) if sessions > 0 => {
+                    debug!("Session is active, throttling");
+                    reason = match &config.session_source {
+                        SessionSource::Jellyfin if light_only => format!("{sessions} sessions matching {}, all from light users", config.jellyfin_activity_policy),
+                        SessionSource::Jellyfin => format!("{sessions} sessions matching {}", config.jellyfin_activity_policy),
+                        SessionSource::Generic(_) => format!("{sessions} sessions from the generic source"),
+                        SessionSource::Command(_) => format!("{sessions} sessions from SESSION_COMMAND"),
+                    };
+                    let limits = if light_only { TransferLimits::light(&config) } else { TransferLimits::throttled(&config) };
+                    if config.throttle_scale_by_sessions {
+                        //That many at once is more likely ghost sessions than real viewers, so they don't scale the limit down any further
+                        if sessions > config.jellyfin_max_sessions && !over_max_sessions {
+                            warn!("{sessions} active sessions is over JELLYFIN_MAX_SESSIONS, scaling for {} until it drops back", config.jellyfin_max_sessions);
+                        }
+                        over_max_sessions = sessions > config.jellyfin_max_sessions;
+                        let sessions = sessions.min(config.jellyfin_max_sessions);
+                        let smoothed = smoothed_sessions.map_or(sessions as f64, |previous| {
+                            config.throttle_smoothing * previous + (1.0 - config.throttle_smoothing) * sessions as f64
+                        });
+                        smoothed_sessions = Some(smoothed);
+                        debug!("Scaling limits for {smoothed:.2} sessions");
+                        Some(limits.scaled(smoothed))
+                    } else {
+                        Some(limits)
+                    }
                 }
-            }
+                Some(_) => {
+                    debug!("Session is not active, removing throttling");
+                    if reason.is_empty() {
+                        reason = "No active sessions".to_string();
+                    }
+                    //The next stream starts from its own count rather than decaying from the last one
+                    smoothed_sessions = None;
+                    Some(TransferLimits::idle(&config))
+                }
+                None => None
+            };
 
-            tokio::time::sleep(Duration::from_secs(config.poll_time_secs)).await;
-        }
-    }
+            //Only needed for the status page, skipping the throttle and the upload cap, so it isn't read when none of them are on
+            //Read while observing too, so the throttle conditions and the upload cap play out like they would for real
+            let transfer_info = match &auth {
+                _ if config.status_bind.is_empty() && config.engage_conditions.is_empty() && config.skip_connection_statuses.is_empty() && upload_cap.is_none() => None,
+                Some(auth) => match qb_get_transfer_info(&client, &config, auth, &mut last_transfer_info_response).await {
+                    Ok(transfer_info) => Some(transfer_info),
+                    Err(err) => {
+                        debug!("Failed to read qBittorrent's transfer info: {err}");
+                        //Writes notice an expired cookie themselves, but observing doesn't write
+                        relogin |= config.observe_only && matches!(err, ThrottlerError::BadResponse { status, .. } if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN);
+                        None
+                    }
+                },
+                None => None
+            };
 
-    0.into()
-}
+            if let (Some(upload_cap), Some(session_total)) = (&mut upload_cap, transfer_info.as_ref().and_then(|transfer_info| transfer_info.up_info_data)) {
+                upload_cap.record(session_total);
+            }
 
-fn get_log_level() -> Level {
-    let mut log_level = Level::INFO;
-    let env_log_level = env::var("QB_THROTTLER_LOG_LEVEL");
-    let dot_env_log_level = dotenv::var("QB_THROTTLER_LOG_LEVEL");
+            //Engaging the throttle while qBittorrent is barely uploading would change nothing
+            let upload_speed = transfer_info.as_ref().and_then(|transfer_info| transfer_info.up_info_speed);
+            let limits = match (limits, upload_speed) {
+                (Some(limits), Some(upload_speed)) if sessions.is_some_and(|sessions| sessions > 0) && !runtime_state.lock().unwrap().throttled => {
+                    match config.engage_conditions.iter().find_map(|condition| condition.unmet(upload_speed, limits)) {
+                        Some(unmet) => {
+                            debug!("Uploading at {upload_speed} B/s which is {unmet}, not throttling yet");
+                            None
+                        }
+                        None => Some(limits)
+                    }
+                }
+                (limits, _) => limits
+            };
 
-    if env_log_level.is_ok() {
-        log_level = Level::from_str(&env_log_level.unwrap()).unwrap_or(log_level);
-    }
+            let limits = match config.throttle_force {
+                ThrottleForce::Auto => limits,
+                ThrottleForce::Always => {
+                    reason = "QB_THROTTLE_FORCE is always".to_string();
+                    Some(TransferLimits::throttled(&config))
+                }
+                ThrottleForce::Never => {
+                    reason = "QB_THROTTLE_FORCE is never".to_string();
+                    Some(TransferLimits::idle(&config))
+                }
+            };
 
-    if dot_env_log_level.is_ok() {
-        log_level = Level::from_str(&dot_env_log_level.unwrap()).unwrap_or(log_level);
-    }
+            //Limits are moot while qBittorrent can't seed, so writes wait until it's connected again
+            //A failed read keeps whatever was seen last rather than counting as connected
+            let connection_status = match &transfer_info {
+                Some(transfer_info) => transfer_info.connection_status.clone()
+                    .filter(|status| config.skip_connection_statuses.iter().any(|skip| skip.eq_ignore_ascii_case(status))),
+                None => skipped_connection_status.clone()
+            };
+            if connection_status != skipped_connection_status {
+                match &connection_status {
+                    Some(status) => info!("qBittorrent is {status}, not changing limits until it's connected"),
+                    None => info!("qBittorrent is connected again, resuming throttling")
+                }
+                skipped_connection_status = connection_status;
+            }
+            let limits = if skipped_connection_status.is_some() { None } else { limits };
 
-    log_level
-}
+            let paused = !config.pause_file.is_empty() && Path::new(&config.pause_file).exists();
+            if paused != was_paused {
+                if paused {
+                    info!("Pause file {} found, pausing throttling", config.pause_file);
+                } else {
+                    info!("Pause file {} removed, resuming throttling", config.pause_file);
+                }
+                was_paused = paused;
+            }
+            let limits = match (paused, config.pause_clears_throttle) {
+                (true, true) => {
+                    reason = format!("Pause file {} exists", config.pause_file);
+                    Some(TransferLimits::idle(&config))
+                }
+                (true, false) => None,
+                (false, _) => limits
+            };
+
+            //A previous run may have died while throttled, so the first idle poll clears it straight away
+            //instead of waiting out the startup grace period or conservative idle
+            let clear_stale_throttle = config.clear_stale_throttle && config.throttle_force == ThrottleForce::Auto && !stale_throttle_checked && fetched_sessions == Some(0) && !paused;
+            if fetched_sessions.is_some_and(|sessions| sessions > 0) {
+                stale_throttle_checked = true;
+            }
+            let limits = if clear_stale_throttle {
+                info!("No sessions at startup, clearing any throttle left by a previous run");
+                reason = "No sessions at startup, clearing any throttle left by a previous run".to_string();
+                Some(TransferLimits::idle(&config))
+            } else {
+                limits
+            };
 
-fn load_config() -> Result<Config, ExitCode> {
-    let env_vars = env::vars();
-    let dot_env_vars = dotenv::vars();
+            //Applies whether throttled or idle, since the cap is about the month rather than the sessions
+            let limits = match (limits, &upload_cap) {
+                (Some(limits), Some(upload_cap)) => {
+                    let upload = upload_cap.limit(limits.upload);
+                    if upload != limits.upload {
+                        reason = format!("{reason}, upload held to {upload} by QB_MONTHLY_UPLOAD_CAP");
+                    }
+                    Some(TransferLimits { upload, ..limits })
+                }
+                (limits, _) => limits
+            };
 
-    //Start with defaults
-    let mut env_config: HashMap<String, Option<String>> = HashMap::from([
-        ("QB_ADDRESS".to_string(), None),
-        ("QB_USERNAME".to_string(), None),
-        ("QB_PASSWORD".to_string(), None),
-        ("JELLYFIN_ADDR".to_string(), None),
-        ("JELLYFIN_TOKEN".to_string(), None),
-        ("JELLYFIN_ACTIVE_WITHIN_SECS".to_string(), Some("5".to_string())),
-        ("QB_THROTTLER_POLL_FREQ".to_string(), Some("5".to_string()))
-    ]);
+            if in_startup_grace && startup_at.elapsed() >= Duration::from_secs(config.startup_grace_secs) {
+                in_startup_grace = false;
+                info!("Startup grace period of {} seconds ended, normal throttling begins", config.startup_grace_secs);
+            }
+            let in_startup_grace = in_startup_grace && !clear_stale_throttle;
 
-    apply_env(&mut env_config, env_vars);
+            let reapplying = config.reapply_on_login && fresh_login;
+            let (write, deferred) = coalesce_write(
+                limits.map(|limits| (limits, reason.clone())),
+                deferred_limits.take(),
+                last_write.map(|last_write| last_write.elapsed()),
+                Duration::from_secs(config.qb_min_write_interval_secs),
+                in_startup_grace || reapplying
+            );
+            if let (Some((deferred, _)), Some(last_write)) = (&deferred, last_write) {
+                debug!("Last qBittorrent write was {}ms ago, deferring {deferred}", last_write.elapsed().as_millis());
+            }
+            deferred_limits = deferred;
+            let limits = write.map(|(limits, write_reason)| {
+                reason = write_reason;
+                limits
+            });
 
-    //Dotenv is more specific so we override system env with it
-    apply_env(&mut env_config, dot_env_vars);
+            //Having to log in again can mean qBittorrent restarted and came back unthrottled, so whatever was
+            //last applied goes out straight away instead of waiting for the next change
+            let reapplied = match limits {
+                None if reapplying && !paused => runtime_state.lock().unwrap().applied_limits,
+                _ => None
+            };
+            if let Some(reapplied) = reapplied {
+                info!("Logged in to qBittorrent again, re-applying {reapplied}");
+            }
+            let limits = limits.or(reapplied);
 
-    if env_config.iter().any(|x| x.1.is_none()) {
-        for entry in env_config.iter().filter(|x| x.1.is_none()) {
-            error!("Config is missing missing for env variable: {}", entry.0);
-        }
-        return Err(1.into());
-    }
+            let wrote_limit = limits.is_some() && !in_startup_grace;
+            if wrote_limit {
+                last_write = Some(Instant::now());
+            }
+            let set_result = match (limits, write_auth) {
+                (Some(limits), _) if in_startup_grace => {
+                    info!("In startup grace period, not applying {limits}");
+                    Ok(())
+                }
+                (Some(limits), _) if config.observe_only => {
+                    debug!("Observing only, not applying {limits}");
+                    Ok(())
+                }
+                (Some(limits), Some(auth)) => apply_limits(&client, &config, auth, &mut torrent_hashes, &mut torrent_privacy, limits).await,
+                _ => Ok(())
+            };
 
-    Ok(Config {
-        qb_address: env_config["QB_ADDRESS"].as_ref().unwrap().to_string(),
-        qb_username: env_config["QB_USERNAME"].as_ref().unwrap().to_string(),
-        qb_password: env_config["QB_PASSWORD"].as_ref().unwrap().to_string(),
-        jellyfin_address: env_config["JELLYFIN_ADDR"].as_ref().unwrap().to_string(),
-        jellyfin_api_token: env_config["JELLYFIN_TOKEN"].as_ref().unwrap().to_string(),
-        jellyfin_active_within_secs: env_config["JELLYFIN_ACTIVE_WITHIN_SECS"].as_ref().unwrap().trim().parse().unwrap_or_else(|_| {
-            error!("JELLYFIN_ACTIVE_WITHIN_SECS env var was not a valid integer. Defaulting to {DEFAULT_JELLYFIN_ACTIVE_WITHIN_SECS}");
-            DEFAULT_JELLYFIN_ACTIVE_WITHIN_SECS
-        }),
-        poll_time_secs: env_config["QB_THROTTLER_POLL_FREQ"].as_ref().unwrap().trim().parse().unwrap_or_else(|_| {
-            error!("JELLYFIN_ACTIVE_WITHIN_SECS env var was not a valid integer. Defaulting to {DEFAULT_POLL_TIME_SECS}");
-            DEFAULT_POLL_TIME_SECS
-        })
-    })
-}
+            if clear_stale_throttle && set_result.is_ok() {
+                stale_throttle_checked = true;
+            }
+            if let Some(retry) = retry_failed_write(limits.map(|limits| (limits, reason.clone())), wrote_limit && set_result.is_err()) {
+                deferred_limits = Some(retry);
+            }
 
-async fn jellyfin_get_sessions(client: &Client, config: &Config) -> Result<usize, ThrottlerError> {
-    let response = client
-        .get(format!("{}/Sessions?activeWithinSeconds={}", &config.jellyfin_address, config.jellyfin_active_within_secs))
-        .header("Authorization", format!("MediaBrowser Token={}", &config.jellyfin_api_token))
-        .send()
-        .await?.json::<Value>().await?;
-    debug!("{:?}", response);
+            //Both the sessions and any write are needed for the poll to have done its job
+            let poll_succeeded = (fetched_sessions.is_some() || sessions_pinned) && set_result.is_ok();
+            let (throttled, engaged, transitioned) = {
+                let mut state = runtime_state.lock().unwrap();
+                let mut engaged = false;
+                let mut transitioned = false;
+                state.record_poll(sessions, poll_succeeded);
+                state.transfer_info = transfer_info;
+                if last_sessions_response.is_some() {
+                    state.last_sessions_response = last_sessions_response;
+                }
+                if last_transfer_info_response.is_some() {
+                    state.last_transfer_info_response = last_transfer_info_response;
+                }
+                //Re-applying isn't a decision about the sessions, so it's left out of the state
+                let throttling = match config.throttle_force {
+                    ThrottleForce::Auto => sessions.map(|sessions| sessions > 0),
+                    //Pausing with QB_THROTTLER_PAUSE_CLEARS_THROTTLE still wins over forcing
+                    _ if paused => Some(false),
+                    ThrottleForce::Always => Some(true),
+                    ThrottleForce::Never => Some(false),
+                };
+                if let (Some(limits), Some(throttling), true) = (limits, throttling, wrote_limit && set_result.is_ok() && reapplied.is_none()) {
+                    let sessions = sessions.unwrap_or(0);
+                    let previous_limits = state.applied_limits.replace(limits);
+                    state.reason = Some(reason.clone());
+                    if state.set_throttled(throttling, sessions, &reason) {
+                        engaged = throttling;
+                        transitioned = true;
+                        if config.observe_only {
+                            info!("Would have {} with {sessions} sessions ({reason})", if throttling { "throttled" } else { "unthrottled" });
+                        } else {
+                            info!("{} with {limits} ({reason})", if throttling { "Throttled" } else { "Unthrottled" });
+                            if config.verify_after_transition && config.save_path_prefix.is_empty() {
+                                pending_verification = Some((limits, previous_limits));
+                            }
+                        }
+                    }
+                }
+                (state.throttled, engaged, transitioned)
+            };
 
-    //Don't care about session details, we only care if any are active
-    if let Some(session_list) = response.as_array() {
-        Ok(session_list.len())
-    } else {
-        Ok(0)
-    }
-}
+            if let Some(statsd) = &statsd {
+                statsd.gauge("throttled", throttled as u64);
+                if let Some(sessions) = sessions {
+                    statsd.gauge("active_sessions", sessions as u64);
+                }
+                if let Some(upload_cap) = &upload_cap {
+                    statsd.gauge("monthly_uploaded_bytes", upload_cap.uploaded());
+                }
+                if transitioned {
+                    statsd.count("transitions", 1);
+                }
+            }
 
-async fn qb_auth(client: &Client, config: &Config) -> Result<String, ThrottlerError> {
-    let response = client.post(format!("{}/api/v2/auth/login", &config.qb_address))
-        .header("Referer", &config.qb_address)
-        .form(&QBCreds::from(config))
-        .send()
-        .await?;
+            if let (true, Some(auth)) = (config.unforce_torrents, write_auth) {
+                if engaged {
+                    unforce_torrents(&client, &config, auth, &unforced_torrents).await;
+                } else if !throttled {
+                    //Retried every unthrottled poll until it goes through, so nothing is left unforced
+                    restore_forced_torrents(&client, &config, auth, &unforced_torrents).await;
+                }
+            }
 
-    let status = response.status();
-    if status != StatusCode::OK {
-        return Err(ThrottlerError::BadResponse(format!("Bad Response from QBittorrent: {status}"), status));
-    }
+            //Torrents picked up by a refresh while throttled are limited too, so they're tagged as they appear
+            if let (true, Some(auth)) = (!config.throttle_tag.is_empty() && !config.save_path_prefix.is_empty(), write_auth) {
+                if throttled {
+                    let untagged: Vec<String> = torrent_hashes.iter()
+                        .flat_map(|(_, hashes)| hashes)
+                        .filter(|hash| !tagged_torrents.contains(hash))
+                        .cloned()
+                        .collect();
+                    if !untagged.is_empty() {
+                        match qb_set_tag(&client, &config, auth, &untagged, true).await {
+                            Ok(()) => tagged_torrents.extend(untagged),
+                            Err(err) => warn!("Failed to tag {} throttled torrents with {}: {err}", untagged.len(), config.throttle_tag)
+                        }
+                    }
+                } else if !tagged_torrents.is_empty() {
+                    match qb_set_tag(&client, &config, auth, &tagged_torrents, false).await {
+                        Ok(()) => tagged_torrents.clear(),
+                        Err(err) => warn!("Failed to remove {} from {} torrents, retrying next poll: {err}", config.throttle_tag, tagged_torrents.len())
+                    }
+                }
+            }
 
-    debug!("Reponse headers: {:?}", response.headers());
+            if config.stats_interval_secs > 0 && stats_window.0.elapsed() >= Duration::from_secs(config.stats_interval_secs) {
+                let state = runtime_state.lock().unwrap();
+                let window = stats_window.0.elapsed();
+                let throttled = state.throttled_time().saturating_sub(stats_window.1).min(window);
+                info!(
+                    "Over the last {} seconds: throttled for {} seconds, unthrottled for {} seconds, {} transitions",
+                    window.as_secs(),
+                    throttled.as_secs(),
+                    (window - throttled).as_secs(),
+                    state.transition_count - stats_window.2
+                );
+                stats_window = (Instant::now(), state.throttled_time(), state.transition_count);
+            }
 
-    let cookie = response.headers().get("set-cookie");
+            if warming_up && poll_succeeded {
+                warming_up = false;
+                info!("First successful poll, polling every {} seconds from now on", config.poll_time_secs);
+            }
 
-    match cookie {
-        Some(token) => {
-            match token.to_str() {
-                Ok(token_str) => {
-                    Ok(token_str.to_string())
+            match set_result {
+                Ok(()) if wrote_limit => {
+                    post_auth_forbidden = 0;
+                    fresh_login = false;
+                    qb_failover.record_success();
+                    write_failures.record_success();
                 }
-                Err(_) => {
-                    Err(ThrottlerError::NoCookie)
+                Err(err @ ThrottlerError::ReqwestError { .. }) if qb_failover.record_failure(&mut config) => {
+                    write_failures.record_failure(&config, &format!("Failed to apply limits: {err}"));
+                    //The cookie is only good for the instance that issued it
+                    break;
                 }
-            }
+                Err(ThrottlerError::BadResponse { status, .. }) if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN => {
+                    //A rejection on the first write after logging in means re-authing won't help, which is what an IP ban looks like
+                    if fresh_login {
+                        post_auth_forbidden += 1;
+                    }
+                    //Exit the loop to re-auth if auth fails
+                    break;
+                }
+                result => {
+                    if let Err(err) = result {
+                        write_failures.record_failure(&config, &format!("Failed to apply limits: {err}"));
+                    }
+                    if wrote_limit {
+                        fresh_login = false;
+                    }
+                }
+            }
+
+            let poll_interval = Duration::from_secs(if warming_up { config.warmup_poll_secs } else { config.poll_time_secs });
+            let poll_duration = poll_started.elapsed();
+            debug!("Poll took {}ms", poll_duration.as_millis());
+            if let Some(statsd) = &statsd {
+                statsd.timing("poll_duration", poll_duration);
+            }
+            let sleep = if config.align_polls && !poll_interval.is_zero() {
+                //A poll that overran its slot waits for the next boundary, so polls stay on the boundaries
+                if poll_duration >= poll_interval {
+                    warn!("Poll took {}ms which exceeds the poll interval of {} seconds, waiting for the next boundary", poll_duration.as_millis(), poll_interval.as_secs());
+                }
+                until_next_boundary(poll_interval)
+            } else if poll_duration >= poll_interval {
+                warn!("Poll took {}ms which exceeds the poll interval of {} seconds, skipping sleep", poll_duration.as_millis(), poll_interval.as_secs());
+                Duration::ZERO
+            } else {
+                //Sleep for whatever is left of the interval so slow requests don't push every poll back
+                poll_interval - poll_duration
+            };
+            match (sleep.is_zero(), pushed_sessions.as_mut()) {
+                (true, _) => {}
+                //A change pushed by Jellyfin starts the next poll straight away
+                (false, Some(pushed)) => tokio::select! {
+                    _ = tokio::time::sleep(sleep) => {}
+                    Ok(()) = pushed.changed() => debug!("Jellyfin pushed a session change, polling early")
+                },
+                (false, None) => tokio::time::sleep(sleep).await
+            }
+            if relogin {
+                break;
+            }
+        }
+    }
+
+    0.into()
+}
+
+//Time until the wall clock is next on a multiple of the interval, so an interval of 10 seconds polls on :00, :10, :20 and so on
+fn until_next_boundary(interval: Duration) -> Duration {
+    let interval_ms = interval.as_millis().max(1);
+    let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+    Duration::from_millis((interval_ms - now_ms % interval_ms) as u64)
+}
+
+//Logs in once for the commands, which exit rather than retrying
+async fn command_auth(client: &Client, config: &Config) -> Option<QbAuth> {
+    if !config.qb_api_key.is_empty() {
+        return Some(QbAuth::ApiKey(config.qb_api_key.clone()));
+    }
+    match qb_auth(client, config).await {
+        Ok(cookie) => Some(QbAuth::Cookie(cookie)),
+        Err(err) => {
+            error!("Failed to log in to qBittorrent: {err}");
+            None
+        }
+    }
+}
+
+//For compose setups where depends_on only waits for the containers to start. Blocks until both qBittorrent and
+//the session source answer, returning whether they did within QB_THROTTLER_WAIT_FOR_SERVICES
+async fn wait_for_services(client: &Client, session_client: &Client, config: &Config) -> bool {
+    let started = Instant::now();
+    let timeout = Duration::from_secs(config.wait_for_services_secs);
+    //Public on Jellyfin so it answers before the token is checked, the preflight covers that afterwards.
+    //A command has nothing to wait for
+    let session_url = match &config.session_source {
+        SessionSource::Jellyfin => Some(format!("{}/System/Info/Public", config.jellyfin_address.trim_end_matches('/'))),
+        SessionSource::Generic(source) => Some(source.url.clone()),
+        SessionSource::Command(_) => None,
+    };
+    loop {
+        let checks = [
+            ("qBittorrent", service_ready(client.get(format!("{}/api/v2/app/version", config.qb_address))).await),
+            ("the session source", match &session_url {
+                Some(session_url) => service_ready(session_client.get(session_url)).await,
+                None => Ok(())
+            }),
+        ];
+        let waiting_on: Vec<String> = checks.into_iter()
+            .filter_map(|(service, result)| result.err().map(|err| format!("{service} ({err})")))
+            .collect();
+        if waiting_on.is_empty() {
+            info!("qBittorrent and the session source are up after {} seconds", started.elapsed().as_secs());
+            return true;
+        }
+
+        let waiting_on = redact(config, &waiting_on.join(" and "));
+        let remaining = timeout.saturating_sub(started.elapsed());
+        if remaining.is_zero() {
+            if config.wait_for_services_exit {
+                error!("Gave up waiting for {waiting_on} after {} seconds", timeout.as_secs());
+            } else {
+                warn!("Gave up waiting for {waiting_on} after {} seconds, starting anyway", timeout.as_secs());
+            }
+            return false;
+        }
+        info!("Waiting for {waiting_on}, {} of {} seconds so far", started.elapsed().as_secs(), timeout.as_secs());
+        tokio::time::sleep(remaining.min(Duration::from_secs(WAIT_FOR_SERVICES_RETRY_SECS))).await;
+    }
+}
+
+//Any answer short of a server error means it's up, even one turning us away since the credentials are checked later
+async fn service_ready(request: reqwest::RequestBuilder) -> Result<(), String> {
+    match request.timeout(Duration::from_secs(WAIT_FOR_SERVICES_RETRY_SECS)).send().await {
+        Ok(response) if response.status().is_server_error() => Err(response.status().to_string()),
+        Ok(_) => Ok(()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+//The limits command, prints qBittorrent's current global limits and exits
+async fn print_limits(client: &Client, config: &Config) -> ExitCode {
+    let Some(auth) = command_auth(client, config).await else {
+        return 1.into();
+    };
+
+    let limits = tokio::try_join!(
+        qb_get_upload(client, config, &auth),
+        qb_get_download(client, config, &auth),
+        qb_get_alt_speed_enabled(client, config, &auth)
+    );
+    match limits {
+        Ok((upload, download, alt_speed)) => {
+            let describe = |limit: u64| if limit == 0 { "unlimited".to_string() } else { format!("{limit} bytes/s") };
+            println!("Upload limit: {}", describe(upload));
+            println!("Download limit: {}", describe(download));
+            println!("Alternative speed limits: {}", if alt_speed { "enabled" } else { "disabled" });
+            0.into()
+        }
+        Err(err) => {
+            error!("Failed to get limits from qBittorrent: {err}");
+            1.into()
+        }
+    }
+}
+
+//The selftest command, sets a distinctive upload limit, reads it back and puts the original back. Proves the
+//write and read path works against this qBittorrent, including versions that answer 200 without changing anything
+async fn self_test(client: &Client, config: &Config) -> ExitCode {
+    let Some(auth) = command_auth(client, config).await else {
+        return 1.into();
+    };
+
+    let original = match qb_get_upload(client, config, &auth).await {
+        Ok(original) => original,
+        Err(err) => {
+            error!("Self test failed, couldn't read the upload limit: {err}");
+            return 1.into();
+        }
+    };
+    let Ok(original) = u32::try_from(original) else {
+        error!("Self test skipped, the upload limit of {original} is too large to put back afterwards");
+        return 1.into();
+    };
+    //A multiple of 1KiB so it isn't rounded, and not whatever is set already so a no-op can't pass
+    let test_limit = if original == SELF_TEST_UPLOAD_LIMIT { SELF_TEST_UPLOAD_LIMIT + 1024 } else { SELF_TEST_UPLOAD_LIMIT };
+
+    let result = match qb_set_upload(client, config, &auth, test_limit).await {
+        Ok(()) => qb_get_upload(client, config, &auth).await,
+        Err(err) => Err(err),
+    };
+    let restored = qb_set_upload(client, config, &auth, original).await;
+
+    let passed = match result {
+        Ok(effective) if effective == test_limit as u64 => {
+            info!("Self test passed, qBittorrent reported the upload limit of {test_limit} that was set");
+            true
+        }
+        Ok(effective) => {
+            error!("Self test failed, set an upload limit of {test_limit} but qBittorrent reported {effective}");
+            false
+        }
+        Err(err) => {
+            error!("Self test failed: {err}");
+            false
+        }
+    };
+    if let Err(err) = restored {
+        error!("Failed to put the upload limit back to {original}, set it by hand: {err}");
+        return 1.into();
+    }
+    info!("Put the upload limit back to {original}");
+
+    if passed { 0.into() } else { 1.into() }
+}
+
+async fn apply_limits(client: &Client, config: &Config, auth: &QbAuth, torrent_hashes: &mut Option<(Instant, Vec<String>)>, torrent_privacy: &mut HashMap<String, bool>, limits: TransferLimits) -> Result<(), ThrottlerError> {
+    if config.save_path_prefix.is_empty() && config.throttle_via_preferences {
+        qb_set_limit_preferences(client, config, auth, limits).await?;
+        if config.qb_verify_preferences {
+            verify_limit_preferences(client, config, auth, limits).await;
+        }
+        return Ok(());
+    }
+
+    if config.save_path_prefix.is_empty() {
+        //The transfer endpoints need a call per direction, so there's a moment where only upload has changed
+        qb_set_upload(client, config, auth, limits.upload).await?;
+        if let Some(download) = limits.download {
+            qb_set_download(client, config, auth, download).await?;
+        }
+    } else {
+        apply_torrent_limits(client, config, auth, torrent_hashes, torrent_privacy, limits).await?;
+    }
+
+    if let Some(enabled) = limits.scheduler_enabled {
+        qb_set_scheduler(client, config, auth, enabled).await?;
+    }
+
+    Ok(())
+}
+
+//Limits waiting to be written along with why they were picked
+type PendingWrite = (TransferLimits, String);
+
+//Writes too soon after the last one are held back, and the newest wanted limits go out on the first poll after the
+//interval even if that poll wants nothing itself. Returns what to write now and what's left waiting
+fn coalesce_write(wanted: Option<PendingWrite>, waiting: Option<PendingWrite>, since_last_write: Option<Duration>, min_interval: Duration, bypass_interval: bool) -> (Option<PendingWrite>, Option<PendingWrite>) {
+    let write = wanted.or(waiting);
+    match since_last_write {
+        Some(since_last_write) if write.is_some() && !bypass_interval && since_last_write < min_interval => (None, write),
+        _ => (write, None)
+    }
+}
+
+//applied_limits only changes on a successful write, and a failed one waits to go out again next poll even if
+//nothing new is wanted by then, so qBittorrent can't be left on limits we think were replaced
+fn retry_failed_write(written: Option<PendingWrite>, failed: bool) -> Option<PendingWrite> {
+    written.filter(|_| failed)
+}
+
+//setPreferences answers 200 even when qBittorrent clamps or ignores a value, so read the limits back to find out.
+//A mismatch is only warned about since the write itself went through
+async fn verify_limit_preferences(client: &Client, config: &Config, auth: &QbAuth, limits: TransferLimits) {
+    let checks = [
+        ("upload", Some(limits.upload), qb_get_upload(client, config, auth).await),
+        ("download", limits.download, match limits.download {
+            Some(_) => qb_get_download(client, config, auth).await,
+            None => Ok(0)
+        }),
+    ];
+    for (direction, requested, effective) in checks {
+        let Some(requested) = requested else { continue };
+        match effective {
+            Ok(effective) if effective != requested as u64 => {
+                warn!("Requested {direction} limit of {requested} but qBittorrent is using {effective}, it may be constraining the value");
+            }
+            Ok(_) => {}
+            Err(err) => warn!("Failed to read back the {direction} limit: {err}")
+        }
+    }
+}
+
+//A limit back at the previous value suggests qBittorrent didn't take the change, anything else that
+//doesn't match means something besides us changed it
+async fn verify_transition(client: &Client, config: &Config, auth: &QbAuth, expected: TransferLimits, previous: Option<TransferLimits>) {
+    match qb_get_upload(client, config, auth).await {
+        Ok(effective) if effective == expected.upload as u64 => {
+            debug!("Verified upload limit of {effective} after the transition");
+        }
+        Ok(effective) if previous.is_some_and(|previous| effective == previous.upload as u64) => {
+            warn!("Upload limit is still {effective} a poll after changing it to {}, qBittorrent may have rejected the change", expected.upload);
+        }
+        Ok(effective) => {
+            warn!("Upload limit is {effective} a poll after changing it to {}, something else may be changing it", expected.upload);
+        }
+        Err(err) => warn!("Failed to verify the upload limit after the transition: {err}")
+    }
+}
+
+async fn apply_torrent_limits(client: &Client, config: &Config, auth: &QbAuth, torrent_hashes: &mut Option<(Instant, Vec<String>)>, torrent_privacy: &mut HashMap<String, bool>, limits: TransferLimits) -> Result<(), ThrottlerError> {
+    let needs_refresh = match torrent_hashes {
+        Some((fetched_at, _)) => fetched_at.elapsed() >= Duration::from_secs(config.torrent_refresh_secs),
+        None => true
+    };
+    let refresh_result = if needs_refresh {
+        qb_get_limited_torrent_hashes(client, config, auth, torrent_privacy).await.map(|hashes| {
+            debug!("{} torrents match save path prefix {} in states {:?}", hashes.len(), config.save_path_prefix, config.throttle_torrent_states);
+            *torrent_hashes = Some((Instant::now(), hashes));
+        })
+    } else {
+        Ok(())
+    };
+
+    //Fall back to the stale list if the refresh failed, only give up if there's nothing cached
+    match (refresh_result, &*torrent_hashes) {
+        (Err(err), None) => Err(err),
+        (result, Some((_, hashes))) => {
+            if let Err(err) = result {
+                error!("Failed to refresh torrent list, using cached list: {err}");
+            }
+            qb_set_torrent_limits(client, config, auth, hashes, limits).await
+        }
+        (Ok(()), None) => Ok(())
+    }
+}
+
+//Exits the process if the poll loop stops checking in, in case an await hangs despite the timeouts.
+//The loop feeds it each iteration and before any deliberate long wait
+#[derive(Clone)]
+struct Watchdog {
+    deadline: Arc<Mutex<Instant>>,
+    timeout: Duration,
+}
+
+impl Watchdog {
+    fn new(timeout: Duration) -> Self {
+        Watchdog {
+            deadline: Arc::new(Mutex::new(Instant::now() + timeout)),
+            timeout
+        }
+    }
+
+    fn feed(&self, allowance: Duration) {
+        *self.deadline.lock().unwrap() = Instant::now() + self.timeout + allowance;
+    }
+
+    async fn watch(self) {
+        loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            if Instant::now() > *self.deadline.lock().unwrap() {
+                error!("Poll loop hasn't checked in for over {} seconds, exiting so a supervisor can restart us", self.timeout.as_secs());
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+//Consecutive failures of something that's expected to fail now and then. They're warnings until there have been
+//QB_THROTTLER_ERROR_AFTER in a row, so a short outage doesn't fill the logs with errors
+#[derive(Default)]
+struct FailureStreak {
+    failures: u32,
+}
+
+impl FailureStreak {
+    fn record_success(&mut self) {
+        self.failures = 0;
+    }
+
+    fn record_failure(&mut self, config: &Config, message: &str) {
+        self.failures = self.failures.saturating_add(1);
+        if self.failures >= config.error_after_failures {
+            error!("{message}");
+        } else {
+            warn!("{message} ({} of {} failures in a row before this is an error)", self.failures, config.error_after_failures);
+        }
+    }
+}
+
+//Which of QB_ADDRESS and QB_ADDRESS_FALLBACK is in use. Only connection failures count towards failing over,
+//since an error response means the instance is up
+struct QbFailover {
+    primary: String,
+    fallback: String,
+    failures: u32,
+    on_fallback: bool,
+}
+
+impl QbFailover {
+    fn new(config: &Config) -> Self {
+        QbFailover {
+            primary: config.qb_address.clone(),
+            fallback: config.qb_address_fallback.clone(),
+            failures: 0,
+            on_fallback: false,
+        }
+    }
+
+    fn record_success(&mut self) {
+        self.failures = 0;
+    }
+
+    //Returns true when this failure switched config over to the fallback
+    fn record_failure(&mut self, config: &mut Config) -> bool {
+        if self.fallback.is_empty() || self.on_fallback {
+            return false;
+        }
+
+        self.failures += 1;
+        if self.failures < config.qb_failover_after {
+            return false;
+        }
+
+        warn!(
+            "qBittorrent at {} failed {} times in a row, failing over to {}",
+            redact_url(&self.primary), self.failures, redact_url(&self.fallback)
+        );
+        self.failures = 0;
+        self.on_fallback = true;
+        config.qb_address = self.fallback.clone();
+        true
+    }
+
+    //Any response at all means the primary is back, login is what decides if it's usable. The probe runs every poll
+    //while on the fallback, so it gets a short timeout rather than holding the poll up while the primary is down
+    async fn primary_recovered(&self, client: &Client) -> bool {
+        self.on_fallback && client.get(format!("{}/api/v2/app/version", &self.primary))
+            .timeout(Duration::from_secs(FAILBACK_PROBE_TIMEOUT_SECS))
+            .send().await.is_ok()
+    }
+
+    fn fail_back(&mut self, config: &mut Config) {
+        info!("qBittorrent at {} is reachable again, failing back from {}", redact_url(&self.primary), redact_url(&self.fallback));
+        self.on_fallback = false;
+        config.qb_address = self.primary.clone();
+    }
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(err) => {
+                error!("Failed to listen for SIGTERM: {err}");
+                let _ = tokio::signal::ctrl_c().await;
+                return;
+            }
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+//Prints the status as a line of JSON on stdout every time SIGUSR1 arrives. There's no SIGUSR1 elsewhere, so it does nothing there
+async fn print_status_on_sigusr1(state: SharedState) {
+    #[cfg(unix)]
+    {
+        let mut user_defined = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1()) {
+            Ok(user_defined) => user_defined,
+            Err(err) => {
+                error!("Failed to listen for SIGUSR1: {err}");
+                return;
+            }
+        };
+        while user_defined.recv().await.is_some() {
+            let line = render_status_line(&state.lock().unwrap());
+            println!("{line}");
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = state;
+}
+
+async fn observe_summary_on_shutdown(state: SharedState) {
+    shutdown_signal().await;
+    let state = state.lock().unwrap();
+    info!(
+        "Observed for {} seconds, would have throttled for {} seconds over {} transitions",
+        state.started_at.elapsed().as_secs(),
+        state.throttled_time().as_secs(),
+        state.transition_count
+    );
+    std::process::exit(0);
+}
+
+//Forced torrents that were switched to normal for the throttle to apply to them, along with the config
+//and auth to re-force them with. The hashes are logged when they're unforced, so if we're killed
+//outright before re-forcing them they can be re-forced by hand
+type UnforcedTorrents = Arc<Mutex<Option<Unforced>>>;
+
+struct Unforced {
+    config: Config,
+    auth: QbAuth,
+    hashes: Vec<String>,
+}
+
+async fn unforce_torrents(client: &Client, config: &Config, auth: &QbAuth, unforced: &UnforcedTorrents) {
+    let hashes = match qb_get_forced_torrent_hashes(client, config, auth).await {
+        Ok(hashes) if hashes.is_empty() => return,
+        Ok(hashes) => hashes,
+        Err(err) => {
+            error!("Failed to find forced torrents, they'll ignore the throttle: {err}");
+            return;
+        }
+    };
+    if let Err(err) = qb_set_force_start(client, config, auth, &hashes, false).await {
+        error!("Failed to unforce {} torrents, they'll ignore the throttle: {err}", hashes.len());
+        return;
+    }
+    info!("Unforced {} torrents while throttled, they'll be forced again after: {}", hashes.len(), hashes.join(", "));
+
+    //Anything still waiting to be re-forced from an earlier throttle is kept
+    let mut unforced = unforced.lock().unwrap();
+    let mut all_hashes = unforced.take().map(|unforced| unforced.hashes).unwrap_or_default();
+    for hash in hashes {
+        if !all_hashes.contains(&hash) {
+            all_hashes.push(hash);
+        }
+    }
+    *unforced = Some(Unforced { config: config.clone(), auth: auth.clone(), hashes: all_hashes });
+}
+
+async fn restore_forced_torrents(client: &Client, config: &Config, auth: &QbAuth, unforced: &UnforcedTorrents) {
+    let Some(hashes) = unforced.lock().unwrap().as_ref().map(|unforced| unforced.hashes.clone()) else { return };
+    match qb_set_force_start(client, config, auth, &hashes, true).await {
+        Ok(()) => {
+            info!("Forced {} torrents again after the throttle", hashes.len());
+            *unforced.lock().unwrap() = None;
+        }
+        Err(err) => error!("Failed to force {} torrents again, retrying next poll: {err}", hashes.len())
+    }
+}
+
+//Torrents can't be left unforced just because we were stopped mid throttle
+async fn restore_forced_on_shutdown(client: Client, unforced: UnforcedTorrents) {
+    shutdown_signal().await;
+    let pending = unforced.lock().unwrap().take();
+    if let Some(Unforced { config, auth, hashes }) = pending {
+        match qb_set_force_start(&client, &config, &auth, &hashes, true).await {
+            Ok(()) => info!("Forced {} torrents again before exiting", hashes.len()),
+            Err(err) => error!("Failed to force torrents again before exiting, they need forcing by hand: {}. {err}", hashes.join(", "))
+        }
+    }
+    std::process::exit(0);
+}
+
+//Only a couple of requests are made per poll, so a small pool that outlives the poll interval
+//means each poll reuses a warm connection. Keepalive stops NAT/firewalls dropping it in between.
+//A longer idle timeout holds sockets open on both servers for longer, 0 keeps them forever
+fn build_client(config: &Config, tls: &Option<TlsConnector>) -> Result<Client, Error> {
+    let pool_idle_timeout = match config.pool_idle_timeout_secs {
+        0 => None,
+        secs => Some(Duration::from_secs(secs))
+    };
+
+    let builder = Client::builder()
+        .pool_idle_timeout(pool_idle_timeout)
+        .pool_max_idle_per_host(config.pool_max_idle)
+        .tcp_keepalive(Duration::from_secs(TCP_KEEPALIVE_SECS))
+        .default_headers(config.extra_headers.clone());
+    match tls {
+        Some(tls) => builder.use_preconfigured_tls(tls.clone()).build(),
+        None => builder.build()
+    }
+}
+
+//Name: value pairs separated by ;, like GENERIC_SESSIONS_HEADERS. Values are marked sensitive since they're
+//usually secrets, which keeps them out of debug output
+fn parse_extra_headers(value: &str) -> Result<HeaderMap, String> {
+    let mut headers = HeaderMap::new();
+    for header in value.split(';').filter(|header| !header.trim().is_empty()) {
+        let (name, value) = header.split_once(':')
+            .ok_or_else(|| format!("QB_THROTTLER_EXTRA_HEADERS entry {} is not in the form Name: value", header.trim()))?;
+        let name = HeaderName::from_str(name.trim())
+            .map_err(|_| format!("QB_THROTTLER_EXTRA_HEADERS has an invalid header name {}", name.trim()))?;
+        let mut value = HeaderValue::from_str(value.trim())
+            .map_err(|_| format!("QB_THROTTLER_EXTRA_HEADERS has an invalid value for {name}"))?;
+        value.set_sensitive(true);
+        headers.append(name, value);
+    }
+    Ok(headers)
+}
+
+//Loads the client certificate and key from PEM files. Building the connector is what checks the key
+//belongs to the certificate, so a mismatch fails here rather than on every handshake
+fn client_tls_connector(cert_path: &str, key_path: &str, insecure: bool) -> Result<Option<TlsConnector>, String> {
+    let mut builder = TlsConnector::builder();
+    builder.danger_accept_invalid_certs(insecure);
+    match (cert_path.is_empty(), key_path.is_empty()) {
+        (true, true) if !insecure => return Ok(None),
+        (true, true) => {}
+        (false, false) => {
+            let cert = std::fs::read(cert_path).map_err(|err| format!("Failed to read client certificate {cert_path}: {err}"))?;
+            let key = std::fs::read(key_path).map_err(|err| format!("Failed to read client key {key_path}: {err}"))?;
+            let identity = Identity::from_pkcs8(&cert, &key)
+                .map_err(|err| format!("Failed to load client certificate {cert_path} with key {key_path}, the key has to be PKCS#8 PEM: {err}"))?;
+            builder.identity(identity);
+        }
+        _ => return Err("QB_THROTTLER_CLIENT_CERT and QB_THROTTLER_CLIENT_KEY have to be set together".to_string())
+    }
+
+    builder.build()
+        .map(Some)
+        .map_err(|err| match cert_path {
+            "" => format!("Failed to set up TLS: {err}"),
+            _ => format!("Client certificate {cert_path} doesn't work with key {key_path}: {err}")
+        })
+}
+
+//Strips any password embedded in the address so it doesn't end up in the logs
+fn redact_url(address: &str) -> String {
+    match Url::parse(address) {
+        Ok(mut url) if url.password().is_some() => {
+            let _ = url.set_password(Some("***"));
+            url.to_string()
+        }
+        _ => address.to_string()
+    }
+}
+
+//Masks the Jellyfin token, qBittorrent password/API key and SID cookie values in text about to be logged
+fn redact(config: &Config, text: &str) -> String {
+    if config.log_unredacted {
+        return text.to_string();
+    }
+
+    let redacted = redact_secrets(text, &[&config.jellyfin_api_token, &config.qb_password, &config.qb_api_key]);
+    redact_cookie_value(&redact_cookie_value(&redacted, "SID="), "QBT_SID_")
+}
+
+//Short secrets are left alone, a password like "1" would otherwise mask every 1 in the logs and /debug/last
+fn redact_secrets(text: &str, secrets: &[&str]) -> String {
+    secrets.iter()
+        .filter(|secret| secret.len() >= MIN_REDACTED_SECRET_LEN)
+        .fold(text.to_string(), |redacted, secret| redacted.replace(secret, "***"))
+}
+
+//The cookie name is matched ignoring case since proxies have been seen to change it
+fn redact_cookie_value(text: &str, cookie_prefix: &str) -> String {
+    let cookie_prefix = cookie_prefix.to_ascii_lowercase();
+    let mut redacted = String::with_capacity(text.len());
+    let mut rest = text;
+    //ASCII lowercasing keeps byte offsets the same, so they can be used on the original
+    while let Some(start) = rest.to_ascii_lowercase().find(&cookie_prefix) {
+        let value_start = start + cookie_prefix.len();
+        redacted.push_str(&rest[..value_start]);
+        redacted.push_str("***");
+        let value_len = rest[value_start..]
+            .find(|c: char| c == ';' || c == '"' || c == ',' || c == '\\' || c.is_whitespace())
+            .unwrap_or(rest.len() - value_start);
+        rest = &rest[value_start + value_len..];
+    }
+    redacted.push_str(rest);
+
+    redacted
+}
+
+fn get_log_level() -> Level {
+    let mut log_level = Level::INFO;
+    let env_log_level = env::var("QB_THROTTLER_LOG_LEVEL");
+    let dot_env_log_level = dotenv::var("QB_THROTTLER_LOG_LEVEL");
+
+    if let Ok(env_log_level) = env_log_level {
+        log_level = Level::from_str(&env_log_level).unwrap_or(log_level);
+    }
+
+    if let Ok(dot_env_log_level) = dot_env_log_level {
+        log_level = Level::from_str(&dot_env_log_level).unwrap_or(log_level);
+    }
+
+    log_level
+}
+
+fn load_config(problems: &mut ConfigProblems, config_json: Option<String>) -> Option<Config> {
+    //dotenv::vars() quietly gives up on a .env it can't parse, a missing one is fine but a broken one should be said
+    let dotenv_error = match dotenv::dotenv() {
+        Err(err) if !err.not_found() => Some(err),
+        _ => None
+    };
+    let env_vars: Vec<(String, String)> = env::vars().collect();
+    let dot_env_vars: Vec<(String, String)> = dotenv::vars().collect();
+
+    //Start with defaults
+    let mut env_config: HashMap<String, Option<String>> = HashMap::from([
+        ("QB_ADDRESS".to_string(), None),
+        ("QB_USERNAME".to_string(), None),
+        ("QB_PASSWORD".to_string(), None),
+        ("QB_API_KEY".to_string(), Some("".to_string())),
+        ("JELLYFIN_ADDR".to_string(), None),
+        ("JELLYFIN_TOKEN".to_string(), None),
+        ("JELLYFIN_API_KEY".to_string(), Some("".to_string())),
+        ("JELLYFIN_ACTIVE_WITHIN_SECS".to_string(), Some("5".to_string())),
+        ("QB_THROTTLER_POLL_FREQ".to_string(), Some("5".to_string())),
+        ("QB_THROTTLE_SAVE_PATH_PREFIX".to_string(), Some("".to_string())),
+        ("QB_THROTTLE_TORRENT_REFRESH_SECS".to_string(), Some(DEFAULT_TORRENT_REFRESH_SECS.to_string())),
+        ("QB_THROTTLER_STARTUP_GRACE_SECS".to_string(), Some("0".to_string())),
+        ("JELLYFIN_UNREACHABLE_UNTHROTTLE_SECS".to_string(), Some("0".to_string())),
+        ("QB_THROTTLE_VIA_PREFERENCES".to_string(), Some("false".to_string())),
+        ("QB_THROTTLER_EXPAND_VARS".to_string(), Some("true".to_string())),
+        ("QB_THROTTLER_LOG_UNREDACTED".to_string(), Some("false".to_string())),
+        ("QB_IP_BAN_THRESHOLD".to_string(), Some(DEFAULT_IP_BAN_THRESHOLD.to_string())),
+        ("QB_IP_BAN_BACKOFF_SECS".to_string(), Some(DEFAULT_IP_BAN_BACKOFF_SECS.to_string())),
+        ("JELLYFIN_IGNORE_DEVICES".to_string(), Some("".to_string())),
+        ("JELLYFIN_IGNORE_GHOST_SESSIONS".to_string(), Some("true".to_string())),
+        ("QB_THROTTLER_POOL_IDLE_TIMEOUT".to_string(), Some(DEFAULT_POOL_IDLE_TIMEOUT_SECS.to_string())),
+        ("QB_THROTTLER_POOL_MAX_IDLE".to_string(), Some(DEFAULT_POOL_MAX_IDLE.to_string())),
+        ("QB_THROTTLER_AUTH_RETRY_SECS".to_string(), Some("".to_string())),
+        ("QB_THROTTLE_DOWNLOAD_LIMIT".to_string(), Some("0".to_string())),
+        ("QB_THROTTLER_LOG_SESSIONS".to_string(), Some("false".to_string())),
+        ("QB_THROTTLER_STATUS_BIND".to_string(), Some("".to_string())),
+        ("QB_SET_LIMIT_RETRIES".to_string(), Some(DEFAULT_RETRY_MAX.to_string())),
+        ("QB_THROTTLER_RETRY_MAX".to_string(), Some("".to_string())),
+        ("QB_THROTTLER_RETRY_BASE_MS".to_string(), Some(DEFAULT_RETRY_BASE_MS.to_string())),
+        ("QB_THROTTLER_RETRY_MULTIPLIER".to_string(), Some(DEFAULT_RETRY_MULTIPLIER.to_string())),
+        ("QB_THROTTLER_RETRY_ON".to_string(), Some("5xx".to_string())),
+        ("JELLYFIN_SESSION_IDLE_SECS".to_string(), Some("0".to_string())),
+        ("QB_THROTTLER_PAUSE_FILE".to_string(), Some("".to_string())),
+        ("QB_THROTTLER_PAUSE_CLEARS_THROTTLE".to_string(), Some("false".to_string())),
+        ("JELLYFIN_LIBRARIES".to_string(), Some("".to_string())),
+        ("JELLYFIN_CLIENTS".to_string(), Some("".to_string())),
+        ("QB_THROTTLER_WATCHDOG_MULTIPLIER".to_string(), Some("0".to_string())),
+        ("QB_THROTTLE_SCHEDULER".to_string(), Some("off".to_string())),
+        ("JELLYFIN_ACTIVE_POLICY".to_string(), Some("any".to_string())),
+        ("JELLYFIN_MIN_BITRATE".to_string(), Some("0".to_string())),
+        ("JELLYFIN_MIN_RESOLUTION".to_string(), Some("0".to_string())),
+        ("JELLYFIN_STARTING_SECS".to_string(), Some("0".to_string())),
+        ("QB_THROTTLE_UPLOAD_LIMIT".to_string(), Some(DEFAULT_THROTTLE_UPLOAD_LIMIT.to_string())),
+        ("QB_IDLE_UPLOAD_LIMIT".to_string(), Some("0".to_string())),
+        ("QB_THROTTLE_LIMIT_CHECK".to_string(), Some("warn".to_string())),
+        ("JELLYFIN_CONSERVATIVE_IDLE".to_string(), Some("false".to_string())),
+        ("QB_REFERER".to_string(), Some("".to_string())),
+        ("QB_THROTTLE_SCALE_BY_SESSIONS".to_string(), Some("false".to_string())),
+        ("QB_THROTTLE_SMOOTHING".to_string(), Some("0".to_string())),
+        ("QB_MIN_WRITE_INTERVAL_SECS".to_string(), Some("0".to_string())),
+        ("JELLYFIN_USER_POLICIES".to_string(), Some("".to_string())),
+        ("QB_LIGHT_UPLOAD_LIMIT".to_string(), Some(DEFAULT_LIGHT_UPLOAD_LIMIT.to_string())),
+        ("JELLYFIN_DETECT_BASE_PATH".to_string(), Some("false".to_string())),
+        ("QB_THROTTLER_OBSERVE_ONLY".to_string(), Some("false".to_string())),
+        ("QB_THROTTLER_SESSION_SOURCE".to_string(), Some("jellyfin".to_string())),
+        ("GENERIC_SESSIONS_URL".to_string(), Some("".to_string())),
+        ("GENERIC_SESSIONS_HEADERS".to_string(), Some("".to_string())),
+        ("GENERIC_SESSIONS_JSON_PATH".to_string(), Some("".to_string())),
+        ("SESSION_COMMAND".to_string(), Some("".to_string())),
+        ("SESSION_COMMAND_TIMEOUT_SECS".to_string(), Some(DEFAULT_SESSION_COMMAND_TIMEOUT_SECS.to_string())),
+        ("QB_VERIFY_PREFERENCES".to_string(), Some("false".to_string())),
+        ("QB_THROTTLER_CLEAR_STALE_THROTTLE".to_string(), Some("true".to_string())),
+        ("QB_ADDRESS_FALLBACK".to_string(), Some("".to_string())),
+        ("QB_FAILOVER_AFTER".to_string(), Some(DEFAULT_QB_FAILOVER_AFTER.to_string())),
+        ("QB_THROTTLER_STATS_INTERVAL_SECS".to_string(), Some("0".to_string())),
+        ("QB_VERIFY_AFTER_TRANSITION".to_string(), Some("false".to_string())),
+        ("JELLYFIN_USE_WEBSOCKET".to_string(), Some("false".to_string())),
+        ("QB_THROTTLER_ERROR_AFTER".to_string(), Some("1".to_string())),
+        ("QB_THROTTLE_SKIP_IF_BELOW_CAP".to_string(), Some("false".to_string())),
+        ("QB_THROTTLE_UNFORCE_TORRENTS".to_string(), Some("false".to_string())),
+        ("QB_THROTTLER_CLIENT_CERT".to_string(), Some("".to_string())),
+        ("QB_THROTTLER_CLIENT_KEY".to_string(), Some("".to_string())),
+        ("QB_INSECURE_TLS".to_string(), Some("false".to_string())),
+        ("JELLYFIN_INSECURE_TLS".to_string(), Some("false".to_string())),
+        ("QB_THROTTLE_TAG".to_string(), Some("".to_string())),
+        ("JELLYFIN_PREFLIGHT".to_string(), Some("true".to_string())),
+        ("QB_LIMIT_FIELD".to_string(), Some("limit".to_string())),
+        ("QB_THROTTLER_ALIGN_POLLS".to_string(), Some("false".to_string())),
+        ("QB_THROTTLE_SKIP_PRIVATE".to_string(), Some("false".to_string())),
+        ("QB_THROTTLE_TORRENT_STATES".to_string(), None),
+        ("QB_THROTTLER_WARMUP_POLL_SECS".to_string(), Some("0".to_string())),
+        ("QB_THROTTLER_EXTRA_HEADERS".to_string(), Some("".to_string())),
+        ("QB_THROTTLE_SKIP_CONNECTION_STATUS".to_string(), Some("".to_string())),
+        ("QB_THROTTLER_DOTENV_STRICT".to_string(), Some("false".to_string())),
+        ("QB_THROTTLE_MIN_UPLOAD_SPEED".to_string(), Some("0".to_string())),
+        ("QB_THROTTLER_STATSD_ADDR".to_string(), Some("".to_string())),
+        ("JELLYFIN_FRESHNESS_FIELD".to_string(), Some("LastPlaybackCheckIn".to_string())),
+        ("JELLYFIN_MAX_SESSIONS".to_string(), Some(DEFAULT_JELLYFIN_MAX_SESSIONS.to_string())),
+        ("QB_REAPPLY_ON_LOGIN".to_string(), Some("true".to_string())),
+        ("JELLYFIN_TOKEN_CHECK_SECS".to_string(), Some(DEFAULT_JELLYFIN_TOKEN_CHECK_SECS.to_string())),
+        ("JELLYFIN_TOKEN_REVOKED_EXIT".to_string(), Some("false".to_string())),
+        ("QB_THROTTLER_DEBUG_ENDPOINT".to_string(), Some("false".to_string())),
+        ("QB_THROTTLE_FORCE".to_string(), Some("auto".to_string())),
+        ("QB_MONTHLY_UPLOAD_CAP".to_string(), Some("0".to_string())),
+        ("QB_UPLOAD_CAP_START_PERCENT".to_string(), Some("80".to_string())),
+        ("QB_UPLOAD_CAP_STATE_FILE".to_string(), Some("".to_string())),
+        ("QB_THROTTLER_WAIT_FOR_SERVICES".to_string(), Some("0".to_string())),
+        ("QB_THROTTLER_WAIT_FOR_SERVICES_EXIT".to_string(), Some("false".to_string()))
+    ]);
+
+    //Config JSON sits between the defaults and the environment, so single variables can still override it
+    let config_json = config_json.or_else(|| dot_env_vars.iter()
+        .find(|(key, _)| key == "QB_THROTTLER_CONFIG_JSON")
+        .map(|(_, value)| value.clone()));
+    if let Some(config_json) = config_json {
+        match parse_config_json(&config_json) {
+            Ok(settings) => {
+                for (key, _) in settings.iter().filter(|(key, _)| !env_config.contains_key(key)) {
+                    problems.defaulted(format!("Config JSON has unknown setting {key}"));
+                }
+                apply_env(&mut env_config, settings.into_iter());
+            }
+            Err(err) => problems.fatal(err)
+        }
+    }
+
+    apply_env(&mut env_config, env_vars.iter().cloned());
+
+    //Dotenv is more specific so we override system env with it
+    apply_env(&mut env_config, dot_env_vars.iter().cloned());
+
+    //JELLYFIN_API_KEY is what other tools call the token, JELLYFIN_TOKEN wins if both are set. An empty
+    //JELLYFIN_TOKEN counts as unset since .env_template ships it that way
+    let jellyfin_api_key = env_config.remove("JELLYFIN_API_KEY").flatten().filter(|key| !key.trim().is_empty());
+    let jellyfin_token_set = env_config["JELLYFIN_TOKEN"].as_ref().is_some_and(|token| !token.trim().is_empty());
+    match (jellyfin_token_set, jellyfin_api_key) {
+        (true, Some(_)) => info!("Using the Jellyfin token from JELLYFIN_TOKEN, ignoring JELLYFIN_API_KEY"),
+        (true, None) => info!("Using the Jellyfin token from JELLYFIN_TOKEN"),
+        (false, Some(api_key)) => {
+            info!("Using the Jellyfin token from JELLYFIN_API_KEY");
+            env_config.insert("JELLYFIN_TOKEN".to_string(), Some(api_key));
         }
-        None => {
+        (false, None) => {}
+    }
+
+    if let Some(err) = dotenv_error {
+        let problem = format!("Failed to load .env, nothing from the failing line on was set: {err}");
+        if parse_env_bool(&env_config, problems, "QB_THROTTLER_DOTENV_STRICT", false) {
+            problems.fatal(problem);
+        } else {
+            problems.defaulted(problem);
+        }
+    }
+
+    //Jellyfin isn't needed when sessions come from elsewhere
+    let session_source = env_config["QB_THROTTLER_SESSION_SOURCE"].as_ref().unwrap().trim().to_lowercase();
+    if session_source != "jellyfin" {
+        for key in ["JELLYFIN_ADDR", "JELLYFIN_TOKEN"] {
+            env_config.get_mut(key).unwrap().get_or_insert_with(String::new);
+        }
+    }
+
+    //The default depends on QB_THROTTLE_DOWNLOAD_LIMIT, so remember whether it was set before filling it in
+    let torrent_states_set = env_config["QB_THROTTLE_TORRENT_STATES"].is_some();
+    env_config.get_mut("QB_THROTTLE_TORRENT_STATES").unwrap().get_or_insert_with(|| DEFAULT_THROTTLE_TORRENT_STATES.to_string());
+
+    //Filled in with empty values so the rest of the config can still be checked
+    for (key, value) in env_config.iter_mut().filter(|x| x.1.is_none()) {
+        problems.fatal(format!("Config is missing missing for env variable: {key}"));
+        *value = Some(String::new());
+    }
+
+    if parse_env_bool(&env_config, problems, "QB_THROTTLER_EXPAND_VARS", true) {
+        //References can point at any variable, not just ones we read, with dotenv winning like above
+        let lookup: HashMap<String, String> = env_vars.into_iter().chain(dot_env_vars).collect();
+        for (key, value) in env_config.iter_mut() {
+            let Some(value) = value else { continue };
+            match expand_vars(value, &lookup) {
+                Ok(expanded) => *value = expanded,
+                Err(err) => problems.fatal(format!("Failed to expand {key}: {err}"))
+            }
+        }
+    }
+
+    let poll_time_secs = parse_env_u64(&env_config, problems, "QB_THROTTLER_POLL_FREQ", DEFAULT_POLL_TIME_SECS);
+    let throttle_download_limit = parse_env_u32(&env_config, problems, "QB_THROTTLE_DOWNLOAD_LIMIT", 0);
+    let mut throttle_torrent_states = parse_env_list(&env_config, "QB_THROTTLE_TORRENT_STATES");
+    if !torrent_states_set && throttle_download_limit > 0 {
+        throttle_torrent_states.extend(DOWNLOADING_TORRENT_STATES.split(',').map(str::to_string));
+    }
+    //Auth retries follow the poll frequency unless set separately
+    let auth_retry_secs = if env_config["QB_THROTTLER_AUTH_RETRY_SECS"].as_ref().unwrap().trim().is_empty() {
+        poll_time_secs
+    } else {
+        parse_env_u64(&env_config, problems, "QB_THROTTLER_AUTH_RETRY_SECS", poll_time_secs)
+    };
+
+    let idle_upload_limit = parse_env_u32(&env_config, problems, "QB_IDLE_UPLOAD_LIMIT", 0);
+    //A percentage like 10% is of the idle limit, which has to be set for there to be anything to take a percentage of
+    let throttle_upload_limit = match env_config["QB_THROTTLE_UPLOAD_LIMIT"].as_ref().unwrap().trim().strip_suffix('%') {
+        Some(percent) => match (percent.trim().parse::<f64>(), idle_upload_limit) {
+            (Ok(percent), idle) if idle > 0 && percent > 0.0 && percent <= 100.0 => (idle as f64 * percent / 100.0).round().max(1.0) as u32,
+            (Ok(_), 0) => {
+                problems.fatal("QB_THROTTLE_UPLOAD_LIMIT is a percentage of QB_IDLE_UPLOAD_LIMIT, which is unlimited. Set QB_IDLE_UPLOAD_LIMIT or give an absolute limit".to_string());
+                DEFAULT_THROTTLE_UPLOAD_LIMIT
+            }
+            _ => {
+                problems.fatal("QB_THROTTLE_UPLOAD_LIMIT env var was not a percentage between 0 and 100".to_string());
+                DEFAULT_THROTTLE_UPLOAD_LIMIT
+            }
+        },
+        None => parse_env_u32(&env_config, problems, "QB_THROTTLE_UPLOAD_LIMIT", DEFAULT_THROTTLE_UPLOAD_LIMIT)
+    };
+    //0 is unlimited, so a throttle of 0 never lowers anything and any other throttle is lower than an idle of 0
+    let throttle_is_lower = match (throttle_upload_limit, idle_upload_limit) {
+        (0, _) => false,
+        (_, 0) => true,
+        (throttle, idle) => throttle < idle
+    };
+    if !throttle_is_lower {
+        let message = format!(
+            "QB_THROTTLE_UPLOAD_LIMIT of {throttle_upload_limit} is not lower than QB_IDLE_UPLOAD_LIMIT of {idle_upload_limit} (0 is unlimited), streaming won't reduce uploads"
+        );
+        match env_config["QB_THROTTLE_LIMIT_CHECK"].as_ref().unwrap().trim().to_lowercase().as_str() {
+            "error" => problems.fatal(message),
+            "off" => {}
+            _ => warn!("{message}")
+        }
+    }
+
+    let session_source = match session_source.as_str() {
+        "jellyfin" => SessionSource::Jellyfin,
+        "generic" => {
+            let url = env_config["GENERIC_SESSIONS_URL"].as_ref().unwrap().trim().to_string();
+            if url.is_empty() {
+                problems.fatal("GENERIC_SESSIONS_URL env var is required for the generic session source".to_string());
+            }
+            let mut headers = Vec::new();
+            for header in env_config["GENERIC_SESSIONS_HEADERS"].as_ref().unwrap().split(';').filter(|header| !header.trim().is_empty()) {
+                match header.split_once(':') {
+                    Some((name, value)) => headers.push((name.trim().to_string(), value.trim().to_string())),
+                    None => problems.fatal(format!("GENERIC_SESSIONS_HEADERS entry {header} is not in the form Name: value"))
+                }
+            }
+            match env_config["GENERIC_SESSIONS_JSON_PATH"].as_ref().unwrap().parse() {
+                Ok(path) => SessionSource::Generic(GenericSource { url, headers, path }),
+                Err(err) => {
+                    problems.fatal(format!("GENERIC_SESSIONS_JSON_PATH env var was invalid: {err}"));
+                    SessionSource::Jellyfin
+                }
+            }
+        }
+        "command" => {
+            let command = env_config["SESSION_COMMAND"].as_ref().unwrap().trim().to_string();
+            if command.is_empty() {
+                problems.fatal("SESSION_COMMAND env var is required for the command session source".to_string());
+            }
+            //A command that never finishes would otherwise hold up every poll
+            let timeout = Duration::from_secs(parse_env_u64(&env_config, problems, "SESSION_COMMAND_TIMEOUT_SECS", DEFAULT_SESSION_COMMAND_TIMEOUT_SECS).max(1));
+            SessionSource::Command(CommandSource { command, timeout })
+        }
+        other => {
+            problems.fatal(format!("QB_THROTTLER_SESSION_SOURCE env var was not one of jellyfin, generic or command: {other}"));
+            SessionSource::Jellyfin
+        }
+    };
+
+    let mut jellyfin_user_policies = Vec::new();
+    for entry in parse_env_list(&env_config, "JELLYFIN_USER_POLICIES") {
+        let parsed = entry.split_once(':')
+            .ok_or_else(|| format!("{entry} is not in the form user:policy"))
+            .and_then(|(user, policy)| Ok((user.trim().to_string(), policy.parse::<UserPolicy>()?)));
+        match parsed {
+            Ok(user_policy) => jellyfin_user_policies.push(user_policy),
+            Err(err) => problems.fatal(format!("JELLYFIN_USER_POLICIES env var was invalid: {err}"))
+        }
+    }
+
+    //QB_SET_LIMIT_RETRIES came first when only limit writes were retried, it's still the default
+    let max_retries = if env_config["QB_THROTTLER_RETRY_MAX"].as_ref().unwrap().trim().is_empty() {
+        parse_env_u32(&env_config, problems, "QB_SET_LIMIT_RETRIES", DEFAULT_RETRY_MAX)
+    } else {
+        parse_env_u32(&env_config, problems, "QB_THROTTLER_RETRY_MAX", DEFAULT_RETRY_MAX)
+    };
+    let mut retry_on = Vec::new();
+    for condition in parse_env_list(&env_config, "QB_THROTTLER_RETRY_ON") {
+        match condition.parse() {
+            Ok(condition) => retry_on.push(condition),
+            Err(err) => problems.fatal(format!("QB_THROTTLER_RETRY_ON env var was invalid: {err}"))
+        }
+    }
+    //Below 1 later retries would come quicker than earlier ones
+    let retry_multiplier = parse_env_f64(&env_config, problems, "QB_THROTTLER_RETRY_MULTIPLIER", DEFAULT_RETRY_MULTIPLIER).max(1.0);
+    if retry_multiplier > MAX_RETRY_MULTIPLIER {
+        problems.fatal(format!("QB_THROTTLER_RETRY_MULTIPLIER env var was over {MAX_RETRY_MULTIPLIER}"));
+    }
+    let retry_policy = RetryPolicy {
+        max_retries,
+        base_delay: Duration::from_millis(parse_env_u64(&env_config, problems, "QB_THROTTLER_RETRY_BASE_MS", DEFAULT_RETRY_BASE_MS)),
+        multiplier: retry_multiplier,
+        retry_on,
+    };
+
+    //reqwest's certificate checks are per client, so each service gets its own connector
+    let client_cert = env_config["QB_THROTTLER_CLIENT_CERT"].as_ref().unwrap().trim();
+    let client_key = env_config["QB_THROTTLER_CLIENT_KEY"].as_ref().unwrap().trim();
+    let mut tls_for = |insecure_key: &str, service: &str| {
+        let insecure = parse_env_bool(&env_config, problems, insecure_key, false);
+        if insecure {
+            warn!("{insecure_key} is set, {service}'s TLS certificate won't be verified");
+        }
+        client_tls_connector(client_cert, client_key, insecure).unwrap_or_else(|err| {
+            problems.fatal(err);
+            None
+        })
+    };
+    let qb_tls = tls_for("QB_INSECURE_TLS", "qBittorrent");
+    //Covers the generic session source too, since it's whatever the sessions come from
+    let session_tls = tls_for("JELLYFIN_INSECURE_TLS", "the session source");
+
+    let mut engage_conditions = Vec::new();
+    if parse_env_bool(&env_config, problems, "QB_THROTTLE_SKIP_IF_BELOW_CAP", false) {
+        engage_conditions.push(EngageCondition::AboveCap);
+    }
+    let min_upload_speed = parse_env_u64(&env_config, problems, "QB_THROTTLE_MIN_UPLOAD_SPEED", 0);
+    if min_upload_speed > 0 {
+        engage_conditions.push(EngageCondition::AboveFloor(min_upload_speed));
+    }
+
+    let extra_headers = parse_extra_headers(env_config["QB_THROTTLER_EXTRA_HEADERS"].as_ref().unwrap()).unwrap_or_else(|err| {
+        problems.fatal(err);
+        HeaderMap::new()
+    });
+
+    let jellyfin_freshness_field = env_config["JELLYFIN_FRESHNESS_FIELD"].as_ref().unwrap().parse().unwrap_or_else(|err| {
+        problems.fatal(format!("JELLYFIN_FRESHNESS_FIELD env var was invalid: {err}"));
+        FreshnessField::LastPlaybackCheckIn
+    });
+
+    let mut jellyfin_activity_policy = SessionActivityPolicy { clauses: Vec::new(), starting_secs: 0 };
+    for clause in parse_env_list(&env_config, "JELLYFIN_ACTIVE_POLICY") {
+        match clause.split('|').map(str::parse).collect() {
+            Ok(clause) => jellyfin_activity_policy.clauses.push(clause),
+            Err(err) => problems.fatal(format!("JELLYFIN_ACTIVE_POLICY env var was invalid: {err}"))
+        }
+    }
+    let min_bitrate = parse_env_u64(&env_config, problems, "JELLYFIN_MIN_BITRATE", 0);
+    if min_bitrate > 0 {
+        jellyfin_activity_policy.clauses.push(vec![SessionPredicate::MinBitrate(min_bitrate)]);
+    }
+    let min_resolution = parse_env_u32(&env_config, problems, "JELLYFIN_MIN_RESOLUTION", 0);
+    if min_resolution > 0 {
+        jellyfin_activity_policy.clauses.push(vec![SessionPredicate::MinResolution(min_resolution)]);
+    }
+    if jellyfin_activity_policy.clauses.is_empty() {
+        jellyfin_activity_policy = SessionActivityPolicy::default();
+    }
+    jellyfin_activity_policy.starting_secs = parse_env_u64(&env_config, problems, "JELLYFIN_STARTING_SECS", 0);
+
+    let config = Config {
+        qb_address: env_config["QB_ADDRESS"].as_ref().unwrap().to_string(),
+        qb_username: env_config["QB_USERNAME"].as_ref().unwrap().to_string(),
+        qb_password: env_config["QB_PASSWORD"].as_ref().unwrap().to_string(),
+        qb_api_key: env_config["QB_API_KEY"].as_ref().unwrap().trim().to_string(),
+        qb_referer: env_config["QB_REFERER"].as_ref().unwrap().trim().to_string(),
+        jellyfin_address: env_config["JELLYFIN_ADDR"].as_ref().unwrap().to_string(),
+        jellyfin_api_token: env_config["JELLYFIN_TOKEN"].as_ref().unwrap().to_string(),
+        jellyfin_active_within_secs: parse_env_u64(&env_config, problems, "JELLYFIN_ACTIVE_WITHIN_SECS", DEFAULT_JELLYFIN_ACTIVE_WITHIN_SECS),
+        poll_time_secs,
+        save_path_prefix: env_config["QB_THROTTLE_SAVE_PATH_PREFIX"].as_ref().unwrap().trim().to_string(),
+        skip_private_torrents: parse_env_bool(&env_config, problems, "QB_THROTTLE_SKIP_PRIVATE", false),
+        throttle_torrent_states,
+        torrent_refresh_secs: parse_env_u64(&env_config, problems, "QB_THROTTLE_TORRENT_REFRESH_SECS", DEFAULT_TORRENT_REFRESH_SECS),
+        startup_grace_secs: parse_env_u64(&env_config, problems, "QB_THROTTLER_STARTUP_GRACE_SECS", 0),
+        jellyfin_unreachable_unthrottle_secs: parse_env_u64(&env_config, problems, "JELLYFIN_UNREACHABLE_UNTHROTTLE_SECS", 0),
+        throttle_via_preferences: parse_env_bool(&env_config, problems, "QB_THROTTLE_VIA_PREFERENCES", false),
+        log_unredacted: parse_env_bool(&env_config, problems, "QB_THROTTLER_LOG_UNREDACTED", false),
+        ip_ban_threshold: parse_env_u32(&env_config, problems, "QB_IP_BAN_THRESHOLD", DEFAULT_IP_BAN_THRESHOLD).max(1),
+        ip_ban_backoff_secs: parse_env_u64(&env_config, problems, "QB_IP_BAN_BACKOFF_SECS", DEFAULT_IP_BAN_BACKOFF_SECS),
+        jellyfin_ignore_devices: parse_env_list(&env_config, "JELLYFIN_IGNORE_DEVICES"),
+        jellyfin_ignore_ghost_sessions: parse_env_bool(&env_config, problems, "JELLYFIN_IGNORE_GHOST_SESSIONS", true),
+        pool_idle_timeout_secs: parse_env_u64(&env_config, problems, "QB_THROTTLER_POOL_IDLE_TIMEOUT", DEFAULT_POOL_IDLE_TIMEOUT_SECS),
+        pool_max_idle: parse_env_u64(&env_config, problems, "QB_THROTTLER_POOL_MAX_IDLE", DEFAULT_POOL_MAX_IDLE) as usize,
+        auth_retry_secs,
+        warmup_poll_secs: parse_env_u64(&env_config, problems, "QB_THROTTLER_WARMUP_POLL_SECS", 0),
+        throttle_download_limit,
+        log_sessions: parse_env_bool(&env_config, problems, "QB_THROTTLER_LOG_SESSIONS", false),
+        status_bind: env_config["QB_THROTTLER_STATUS_BIND"].as_ref().unwrap().trim().to_string(),
+        retry_policy,
+        jellyfin_session_idle_secs: parse_env_u64(&env_config, problems, "JELLYFIN_SESSION_IDLE_SECS", 0),
+        jellyfin_freshness_field,
+        jellyfin_token_check_secs: parse_env_u64(&env_config, problems, "JELLYFIN_TOKEN_CHECK_SECS", DEFAULT_JELLYFIN_TOKEN_CHECK_SECS),
+        debug_endpoint: parse_env_bool(&env_config, problems, "QB_THROTTLER_DEBUG_ENDPOINT", false),
+        jellyfin_token_revoked_exit: parse_env_bool(&env_config, problems, "JELLYFIN_TOKEN_REVOKED_EXIT", false),
+        wait_for_services_secs: parse_env_u64(&env_config, problems, "QB_THROTTLER_WAIT_FOR_SERVICES", 0),
+        wait_for_services_exit: parse_env_bool(&env_config, problems, "QB_THROTTLER_WAIT_FOR_SERVICES_EXIT", false),
+        reapply_on_login: parse_env_bool(&env_config, problems, "QB_REAPPLY_ON_LOGIN", true),
+        jellyfin_max_sessions: parse_env_u64(&env_config, problems, "JELLYFIN_MAX_SESSIONS", DEFAULT_JELLYFIN_MAX_SESSIONS).max(1) as usize,
+        pause_file: env_config["QB_THROTTLER_PAUSE_FILE"].as_ref().unwrap().trim().to_string(),
+        pause_clears_throttle: parse_env_bool(&env_config, problems, "QB_THROTTLER_PAUSE_CLEARS_THROTTLE", false),
+        jellyfin_libraries: parse_env_list(&env_config, "JELLYFIN_LIBRARIES"),
+        jellyfin_clients: parse_env_list(&env_config, "JELLYFIN_CLIENTS"),
+        jellyfin_activity_policy,
+        jellyfin_user_policies,
+        session_source,
+        verify_after_transition: parse_env_bool(&env_config, problems, "QB_VERIFY_AFTER_TRANSITION", false),
+        jellyfin_use_websocket: parse_env_bool(&env_config, problems, "JELLYFIN_USE_WEBSOCKET", false),
+        error_after_failures: parse_env_u32(&env_config, problems, "QB_THROTTLER_ERROR_AFTER", 1).max(1),
+        engage_conditions,
+        unforce_torrents: parse_env_bool(&env_config, problems, "QB_THROTTLE_UNFORCE_TORRENTS", false),
+        qb_tls,
+        session_tls,
+        extra_headers,
+        statsd_address: env_config["QB_THROTTLER_STATSD_ADDR"].as_ref().unwrap().trim().to_string(),
+        monthly_upload_cap: parse_env_u64(&env_config, problems, "QB_MONTHLY_UPLOAD_CAP", 0),
+        upload_cap_start_percent: parse_env_f64(&env_config, problems, "QB_UPLOAD_CAP_START_PERCENT", 80.0).clamp(0.0, 100.0),
+        upload_cap_state_file: env_config["QB_UPLOAD_CAP_STATE_FILE"].as_ref().unwrap().trim().to_string(),
+        skip_connection_statuses: parse_env_list(&env_config, "QB_THROTTLE_SKIP_CONNECTION_STATUS"),
+        throttle_tag: env_config["QB_THROTTLE_TAG"].as_ref().unwrap().trim().to_string(),
+        jellyfin_preflight: parse_env_bool(&env_config, problems, "JELLYFIN_PREFLIGHT", true),
+        align_polls: parse_env_bool(&env_config, problems, "QB_THROTTLER_ALIGN_POLLS", false),
+        qb_limit_field: match env_config["QB_LIMIT_FIELD"].as_ref().unwrap().trim() {
+            "" => "limit".to_string(),
+            field => field.to_string()
+        },
+        stats_interval_secs: parse_env_u64(&env_config, problems, "QB_THROTTLER_STATS_INTERVAL_SECS", 0),
+        qb_address_fallback: env_config["QB_ADDRESS_FALLBACK"].as_ref().unwrap().trim().to_string(),
+        qb_failover_after: parse_env_u32(&env_config, problems, "QB_FAILOVER_AFTER", DEFAULT_QB_FAILOVER_AFTER).max(1),
+        clear_stale_throttle: parse_env_bool(&env_config, problems, "QB_THROTTLER_CLEAR_STALE_THROTTLE", true),
+        qb_verify_preferences: parse_env_bool(&env_config, problems, "QB_VERIFY_PREFERENCES", false),
+        observe_only: parse_env_bool(&env_config, problems, "QB_THROTTLER_OBSERVE_ONLY", false),
+        jellyfin_detect_base: parse_env_bool(&env_config, problems, "JELLYFIN_DETECT_BASE_PATH", false),
+        light_upload_limit: parse_env_u32(&env_config, problems, "QB_LIGHT_UPLOAD_LIMIT", DEFAULT_LIGHT_UPLOAD_LIMIT),
+        throttle_upload_limit,
+        idle_upload_limit,
+        jellyfin_conservative_idle: parse_env_bool(&env_config, problems, "JELLYFIN_CONSERVATIVE_IDLE", false),
+        throttle_scale_by_sessions: parse_env_bool(&env_config, problems, "QB_THROTTLE_SCALE_BY_SESSIONS", false),
+        qb_min_write_interval_secs: parse_env_u64(&env_config, problems, "QB_MIN_WRITE_INTERVAL_SECS", 0),
+        //A factor of 1 would never move off the first count
+        throttle_smoothing: parse_env_f64(&env_config, problems, "QB_THROTTLE_SMOOTHING", 0.0).clamp(0.0, 0.99),
+        scheduler_action: match env_config["QB_THROTTLE_SCHEDULER"].as_ref().unwrap().trim().to_lowercase().as_str() {
+            "enable" => SchedulerAction::EnableWhenThrottled,
+            "disable" => SchedulerAction::DisableWhenThrottled,
+            "off" => SchedulerAction::Off,
+            other => {
+                problems.defaulted(format!("QB_THROTTLE_SCHEDULER env var was not one of off, enable or disable: {other}. Defaulting to off"));
+                SchedulerAction::Off
+            }
+        },
+        throttle_force: match env_config["QB_THROTTLE_FORCE"].as_ref().unwrap().trim().to_lowercase().as_str() {
+            "auto" => ThrottleForce::Auto,
+            "always" => ThrottleForce::Always,
+            "never" => ThrottleForce::Never,
+            other => {
+                problems.defaulted(format!("QB_THROTTLE_FORCE env var was not one of auto, always or never: {other}. Defaulting to auto"));
+                ThrottleForce::Auto
+            }
+        },
+        //A multiplier of 1 would leave no room for the poll itself on top of the sleep
+        watchdog_multiplier: match parse_env_u64(&env_config, problems, "QB_THROTTLER_WATCHDOG_MULTIPLIER", 0) {
+            0 => 0,
+            multiplier => multiplier.max(2)
+        },
+    };
+    if config.skip_private_torrents && config.save_path_prefix.is_empty() {
+        problems.defaulted("QB_THROTTLE_SKIP_PRIVATE only applies to per-torrent limits, set QB_THROTTLE_SAVE_PATH_PREFIX to use it".to_string());
+    }
+
+    problems.fatal.is_empty().then_some(config)
+}
+
+//Problems found while loading the config, each one is logged as it's found
+#[derive(Default)]
+struct ConfigProblems {
+    //Stop the throttler from starting
+    fatal: Vec<String>,
+    //Fell back to a default so the throttler still starts, only fatal for --validate
+    defaulted: Vec<String>,
+}
+
+impl ConfigProblems {
+    fn fatal(&mut self, problem: String) {
+        error!("{problem}");
+        self.fatal.push(problem);
+    }
+
+    fn defaulted(&mut self, problem: String) {
+        error!("{problem}");
+        self.defaulted.push(problem);
+    }
+
+    //Result of --validate, any problem at all fails it
+    fn report(&self) -> ExitCode {
+        let count = self.fatal.len() + self.defaulted.len();
+        if count == 0 {
+            info!("Config is valid");
+            0.into()
+        } else {
+            error!("Config has {count} problems, {} of which stop the throttler from starting", self.fatal.len());
+            1.into()
+        }
+    }
+}
+
+fn parse_env_list(env_config: &HashMap<String, Option<String>>, key: &str) -> Vec<String> {
+    env_config[key].as_ref().unwrap()
+        .split(',')
+        .map(|entry| entry.trim().to_string())
+        .filter(|entry| !entry.is_empty())
+        .collect()
+}
+
+fn parse_env_bool(env_config: &HashMap<String, Option<String>>, problems: &mut ConfigProblems, key: &str, default: bool) -> bool {
+    match env_config[key].as_ref().unwrap().trim().to_lowercase().as_str() {
+        "true" | "1" | "yes" => true,
+        "false" | "0" | "no" => false,
+        _ => {
+            problems.defaulted(format!("{key} env var was not a valid boolean. Defaulting to {default}"));
+            default
+        }
+    }
+}
+
+fn parse_env_f64(env_config: &HashMap<String, Option<String>>, problems: &mut ConfigProblems, key: &str, default: f64) -> f64 {
+    env_config[key].as_ref().unwrap().trim().parse().ok().filter(|value: &f64| value.is_finite()).unwrap_or_else(|| {
+        problems.defaulted(format!("{key} env var was not a valid number. Defaulting to {default}"));
+        default
+    })
+}
+
+fn parse_env_u64(env_config: &HashMap<String, Option<String>>, problems: &mut ConfigProblems, key: &str, default: u64) -> u64 {
+    env_config[key].as_ref().unwrap().trim().parse().unwrap_or_else(|_| {
+        problems.defaulted(format!("{key} env var was not a valid integer. Defaulting to {default}"));
+        default
+    })
+}
+
+//Values that end up in a u32 are checked rather than cast, a cast would quietly wrap something like 4294967296 to 0
+fn parse_env_u32(env_config: &HashMap<String, Option<String>>, problems: &mut ConfigProblems, key: &str, default: u32) -> u32 {
+    u32::try_from(parse_env_u64(env_config, problems, key, default as u64)).unwrap_or_else(|_| {
+        problems.fatal(format!("{key} env var was over {}", u32::MAX));
+        default
+    })
+}
+
+async fn qb_auth(client: &Client, config: &Config) -> Result<String, ThrottlerError> {
+    let request = client.post(format!("{}/api/v2/auth/login", &config.qb_address))
+        .header("Referer", if config.qb_referer.is_empty() { &config.qb_address } else { &config.qb_referer })
+        .form(&QBCreds::from(config));
+    let response = config.retry_policy.send(request, Operation::Auth).await?;
+
+    let status = response.status();
+    if status != StatusCode::OK {
+        return Err(ThrottlerError::bad_response(Operation::Auth, &response, format!("Bad Response from QBittorrent: {status}")));
+    }
+
+    debug!("Reponse headers: {}", redact(config, &format!("{:?}", response.headers())));
+
+    let cookie = session_cookie(response.headers());
+
+    let body = response.text().await.during(Operation::Auth)?;
+    match (QbLoginBody::from(body.as_str()), cookie) {
+        //qBittorrent answers bad credentials with a 200 and a body of "Fails." rather than a 401
+        (QbLoginBody::Fails, _) => Err(ThrottlerError::CredentialsRejected),
+        (_, Some(cookie)) => Ok(cookie),
+        //Proxies that don't pass Set-Cookie through still pass the body, so this isn't a credentials problem
+        (QbLoginBody::Ok, None) => Err(ThrottlerError::CookieStripped),
+        (QbLoginBody::Other(body), None) => {
+            debug!("Unexpected login response body: {}", redact(config, &body));
             Err(ThrottlerError::NoCookie)
         }
     }
 }
 
-async fn qb_set_upload(client: &Client, config: &Config, cookie: &String, speed: u32) -> Result<(), ThrottlerError> {
-    let mut payload = HashMap::new();
-    payload.insert("limit", speed);
-    let response = client.post(format!("{}/api/v2/transfer/setUploadLimit", &config.qb_address))
-        .header("Cookie", cookie)
-        .form(&payload)
-        .send()
-        .await?;
-    debug!("{response:?}");
-    
+//What qBittorrent says in the body of a login response
+#[derive(Debug, PartialEq)]
+enum QbLoginBody {
+    Ok,
+    Fails,
+    Other(String),
+}
+
+impl From<&str> for QbLoginBody {
+    fn from(body: &str) -> Self {
+        match body.trim() {
+            "Ok." => QbLoginBody::Ok,
+            "Fails." => QbLoginBody::Fails,
+            other => QbLoginBody::Other(other.to_string()),
+        }
+    }
+}
+
+//Picks the session cookie out of the set-cookie headers as a name=value pair for the Cookie header.
+//Header names are case insensitive in reqwest already, the cookie name is matched ignoring case too and
+//newer qBittorrent versions name it QBT_SID_<port>. Other cookies are never taken for the session
+fn session_cookie(headers: &HeaderMap) -> Option<String> {
+    headers.get_all(SET_COOKIE).iter()
+        .filter_map(|header| header.to_str().ok())
+        .filter_map(|header| header.split(';').next())
+        .map(str::trim)
+        .filter(|cookie| cookie.contains('='))
+        .find(|cookie| {
+            let name = cookie.split('=').next().unwrap_or_default().trim().to_ascii_uppercase();
+            name == "SID" || name.starts_with("QBT_SID")
+        })
+        .map(str::to_string)
+}
+
+async fn qb_set_upload(client: &Client, config: &Config, auth: &QbAuth, speed: u32) -> Result<(), ThrottlerError> {
+    qb_post_form(client, config, auth, Operation::SetLimit, "transfer/setUploadLimit", &[(config.qb_limit_field.as_str(), speed.to_string())]).await
+}
+
+async fn qb_set_download(client: &Client, config: &Config, auth: &QbAuth, speed: u32) -> Result<(), ThrottlerError> {
+    qb_post_form(client, config, auth, Operation::SetLimit, "transfer/setDownloadLimit", &[(config.qb_limit_field.as_str(), speed.to_string())]).await
+}
+
+async fn qb_get_upload(client: &Client, config: &Config, auth: &QbAuth) -> Result<u64, ThrottlerError> {
+    qb_get_limit(client, config, auth, "transfer/uploadLimit").await
+}
+
+async fn qb_get_download(client: &Client, config: &Config, auth: &QbAuth) -> Result<u64, ThrottlerError> {
+    qb_get_limit(client, config, auth, "transfer/downloadLimit").await
+}
+
+async fn qb_get_limit(client: &Client, config: &Config, auth: &QbAuth, path: &str) -> Result<u64, ThrottlerError> {
+    Ok(normalize_limit(qb_get_number(client, config, auth, path).await?))
+}
+
+//The transfer endpoints report unlimited as 0, but some versions use -1 and preferences can too. Anything
+//negative is read as 0 so a limit read back and written again can never go out negative
+fn normalize_limit(limit: i64) -> u64 {
+    limit.max(0) as u64
+}
+
+fn deserialize_limit<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    Ok(Option::<i64>::deserialize(deserializer)?.map(normalize_limit))
+}
+
+//speedLimitsMode is 1 while the alternative speed limits are on
+async fn qb_get_alt_speed_enabled(client: &Client, config: &Config, auth: &QbAuth) -> Result<bool, ThrottlerError> {
+    Ok(qb_get_number(client, config, auth, "transfer/speedLimitsMode").await? == 1)
+}
+
+async fn qb_get_transfer_info(client: &Client, config: &Config, auth: &QbAuth, last_response: &mut Option<String>) -> Result<QbTransferInfo, ThrottlerError> {
+    let request = client.get(format!("{}/api/v2/transfer/info", &config.qb_address))
+        .header(auth.header_name(), auth.header_value());
+    let response = config.retry_policy.send(request, Operation::TransferInfo).await?;
+
+    let status = response.status();
+    if status != StatusCode::OK {
+        return Err(ThrottlerError::bad_response(Operation::TransferInfo, &response, format!("Bad Response from QBittorrent: {status}")));
+    }
+
+    let body = response.text().await.during(Operation::TransferInfo)?;
+    if config.debug_endpoint {
+        *last_response = Some(redact(config, &body));
+    }
+    serde_json::from_str(&body)
+        .map_err(|err| ThrottlerError::InvalidResponse(format!("Unexpected transfer info from qBittorrent: {err}")))
+}
+
+//For the endpoints that answer with a bare number in plain text
+async fn qb_get_number(client: &Client, config: &Config, auth: &QbAuth, path: &str) -> Result<i64, ThrottlerError> {
+    let request = client.get(format!("{}/api/v2/{path}", &config.qb_address))
+        .header(auth.header_name(), auth.header_value());
+    let response = config.retry_policy.send(request, Operation::GetLimit).await?;
+
+    let status = response.status();
+    if status != StatusCode::OK {
+        return Err(ThrottlerError::bad_response(Operation::GetLimit, &response, format!("Bad Response from QBittorrent: {status}")));
+    }
+
+    let body = response.text().await.during(Operation::GetLimit)?;
+    body.trim().parse()
+        .map_err(|_| ThrottlerError::InvalidResponse(format!("Expected a number from qBittorrent {path}, got {body}")))
+}
+
+//The torrents per-torrent limits apply to, leaving out private ones with QB_THROTTLE_SKIP_PRIVATE.
+//qBittorrent before 5.0 only says whether a torrent is private in its properties, which is a request per torrent,
+//so the answers are cached by hash and only torrents new since the last refresh cost a request
+async fn qb_get_limited_torrent_hashes(client: &Client, config: &Config, auth: &QbAuth, torrent_privacy: &mut HashMap<String, bool>) -> Result<Vec<String>, ThrottlerError> {
+    //Torrents that leave these states keep their last limit, which only matters once they're back in one and
+    //get the current limit again
+    let torrents: Vec<QBTorrent> = qb_get_torrents(client, config, auth).await?.into_iter()
+        .filter(|torrent| config.throttle_torrent_states.is_empty()
+            || config.throttle_torrent_states.iter().any(|state| state.eq_ignore_ascii_case(&torrent.state)))
+        .collect();
+    if !config.skip_private_torrents {
+        return Ok(torrents.into_iter().map(|torrent| torrent.hash).collect());
+    }
+
+    //Forget torrents that have gone so the cache doesn't grow forever
+    torrent_privacy.retain(|hash, _| torrents.iter().any(|torrent| &torrent.hash == hash));
+    let mut unknown = 0;
+    let mut hashes = Vec::new();
+    for torrent in torrents {
+        let private = match torrent.private.or_else(|| torrent_privacy.get(&torrent.hash).copied()) {
+            Some(private) => private,
+            None => {
+                //Versions too old to report it at all are treated as public, which is how they were throttled before
+                let private = qb_get_torrent_private(client, config, auth, &torrent.hash).await?;
+                unknown += private.is_none() as usize;
+                let private = private.unwrap_or(false);
+                torrent_privacy.insert(torrent.hash.clone(), private);
+                private
+            }
+        };
+        if !private {
+            hashes.push(torrent.hash);
+        }
+    }
+    if unknown > 0 {
+        warn!("qBittorrent didn't say whether {unknown} torrents are private, they're throttled like public ones");
+    }
+    Ok(hashes)
+}
+
+async fn qb_get_torrent_private(client: &Client, config: &Config, auth: &QbAuth, hash: &str) -> Result<Option<bool>, ThrottlerError> {
+    let request = client.get(format!("{}/api/v2/torrents/properties", &config.qb_address))
+        .header(auth.header_name(), auth.header_value())
+        .query(&[("hash", hash)]);
+    let response = config.retry_policy.send(request, Operation::TorrentList).await?;
+
+    let status = response.status();
+    if status != StatusCode::OK {
+        return Err(ThrottlerError::bad_response(Operation::TorrentList, &response, format!("Bad Response from QBittorrent: {status}")));
+    }
+
+    Ok(response.json::<QBTorrentProperties>().await.during(Operation::TorrentList)?.is_private)
+}
+
+async fn qb_get_forced_torrent_hashes(client: &Client, config: &Config, auth: &QbAuth) -> Result<Vec<String>, ThrottlerError> {
+    Ok(qb_get_torrents(client, config, auth).await?.into_iter()
+        .filter(|torrent| torrent.force_start)
+        .map(|torrent| torrent.hash)
+        .collect())
+}
+
+//Torrents under the save path prefix, which is all of them when it's empty
+async fn qb_get_torrents(client: &Client, config: &Config, auth: &QbAuth) -> Result<Vec<QBTorrent>, ThrottlerError> {
+    let request = client.get(format!("{}/api/v2/torrents/info", &config.qb_address))
+        .header(auth.header_name(), auth.header_value());
+    let response = config.retry_policy.send(request, Operation::TorrentList).await?;
+
+    let status = response.status();
+    if status != StatusCode::OK {
+        return Err(ThrottlerError::bad_response(Operation::TorrentList, &response, format!("Bad Response from QBittorrent: {status}")));
+    }
+
+    let torrents = response.json::<Vec<QBTorrent>>().await.during(Operation::TorrentList)?;
+    Ok(torrents.into_iter()
+        .filter(|torrent| torrent.save_path.starts_with(&config.save_path_prefix))
+        .collect())
+}
+
+async fn qb_set_tag(client: &Client, config: &Config, auth: &QbAuth, hashes: &[String], tagged: bool) -> Result<(), ThrottlerError> {
+    let path = if tagged { "torrents/addTags" } else { "torrents/removeTags" };
+    let payload = [("hashes", hashes.join("|")), ("tags", config.throttle_tag.clone())];
+    qb_post_form(client, config, auth, Operation::Tags, path, &payload).await
+}
+
+async fn qb_set_force_start(client: &Client, config: &Config, auth: &QbAuth, hashes: &[String], force_start: bool) -> Result<(), ThrottlerError> {
+    let payload = [("hashes", hashes.join("|")), ("value", force_start.to_string())];
+    qb_post_form(client, config, auth, Operation::ForceStart, "torrents/setForceStart", &payload).await
+}
+
+async fn qb_set_torrent_limits(client: &Client, config: &Config, auth: &QbAuth, hashes: &[String], limits: TransferLimits) -> Result<(), ThrottlerError> {
+    //An empty hash list would be rejected, there's nothing to limit anyway
+    if hashes.is_empty() {
+        return Ok(());
+    }
+
+    let hashes = hashes.join("|");
+    qb_post_form(client, config, auth, Operation::SetLimit, "torrents/setUploadLimit", &[("hashes", hashes.clone()), (config.qb_limit_field.as_str(), limits.upload.to_string())]).await?;
+    if let Some(download) = limits.download {
+        qb_post_form(client, config, auth, Operation::SetLimit, "torrents/setDownloadLimit", &[("hashes", hashes), (config.qb_limit_field.as_str(), download.to_string())]).await?;
+    }
+
+    Ok(())
+}
+
+//Unlike setUploadLimit this changes the up_limit preference, which qBittorrent saves and keeps across restarts.
+//setUploadLimit only changes the limit of the running session, and has been seen not to stick on some versions.
+//Both limits go in the same request, so unlike the transfer endpoints they change together
+async fn qb_set_limit_preferences(client: &Client, config: &Config, auth: &QbAuth, limits: TransferLimits) -> Result<(), ThrottlerError> {
+    qb_set_preferences(client, config, auth, limit_preferences(limits)).await
+}
+
+fn limit_preferences(limits: TransferLimits) -> HashMap<&'static str, serde_json::Value> {
+    let mut changes = HashMap::from([("up_limit", serde_json::Value::from(limits.upload))]);
+    if let Some(download) = limits.download {
+        changes.insert("dl_limit", download.into());
+    }
+    if let Some(enabled) = limits.scheduler_enabled {
+        changes.insert("scheduler_enabled", enabled.into());
+    }
+    changes
+}
+
+async fn qb_set_scheduler(client: &Client, config: &Config, auth: &QbAuth, enabled: bool) -> Result<(), ThrottlerError> {
+    qb_set_preferences(client, config, auth, HashMap::from([("scheduler_enabled", serde_json::Value::from(enabled))])).await
+}
+
+//Every preference that needs changing goes in the one setPreferences call, so they change together and
+//only cost a single request
+async fn qb_set_preferences(client: &Client, config: &Config, auth: &QbAuth, changes: HashMap<&str, serde_json::Value>) -> Result<(), ThrottlerError> {
+    qb_post_form(client, config, auth, Operation::SetLimit, "app/setPreferences", &[("json", preferences_json(changes))]).await
+}
+
+//The json= form field setPreferences takes
+fn preferences_json(changes: HashMap<&str, serde_json::Value>) -> String {
+    let preferences = changes.into_iter().map(|(name, value)| (name.to_string(), value)).collect::<serde_json::Map<_, _>>();
+    serde_json::Value::Object(preferences).to_string()
+}
+
+async fn qb_post_form(client: &Client, config: &Config, auth: &QbAuth, operation: Operation, path: &str, payload: &[(&str, String)]) -> Result<(), ThrottlerError> {
+    let request = client.post(format!("{}/api/v2/{path}", &config.qb_address))
+        .header(auth.header_name(), auth.header_value())
+        .form(payload);
+    let response = config.retry_policy.send(request, operation).await?;
+    debug!("{}", redact(config, &format!("{response:?}")));
+
     let status = response.status();
     if status != StatusCode::OK {
-        return Err(ThrottlerError::BadResponse(format!("Bad Response from QBittorrent: {status}"), status));
+        return Err(ThrottlerError::bad_response(operation, &response, format!("Bad Response from QBittorrent: {status}")));
     }
-    
+
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(upload: u32) -> PendingWrite {
+        (TransferLimits { upload, download: None, scheduler_enabled: None }, format!("upload {upload}"))
+    }
+
+    #[test]
+    fn writes_straight_away_outside_the_min_interval() {
+        let min_interval = Duration::from_secs(30);
+        assert_eq!(coalesce_write(Some(pending(100)), None, None, min_interval, false), (Some(pending(100)), None));
+        assert_eq!(coalesce_write(Some(pending(100)), None, Some(Duration::from_secs(31)), min_interval, false), (Some(pending(100)), None));
+        assert_eq!(coalesce_write(Some(pending(100)), None, Some(Duration::from_secs(5)), Duration::ZERO, false), (Some(pending(100)), None));
+        assert_eq!(coalesce_write(None, None, Some(Duration::from_secs(5)), min_interval, false), (None, None));
+    }
+
+    #[test]
+    fn defers_inside_the_min_interval_and_keeps_the_newest() {
+        let min_interval = Duration::from_secs(30);
+        let (write, waiting) = coalesce_write(Some(pending(100)), None, Some(Duration::from_secs(5)), min_interval, false);
+        assert_eq!((write.clone(), waiting.clone()), (None, Some(pending(100))));
+
+        //A newer change inside the interval replaces what was waiting
+        let (write, waiting) = coalesce_write(Some(pending(200)), waiting, Some(Duration::from_secs(10)), min_interval, false);
+        assert_eq!((write.clone(), waiting.clone()), (None, Some(pending(200))));
+
+        //Nothing new wanted once the interval is up, so the newest waiting limits go out
+        assert_eq!(coalesce_write(None, waiting.clone(), Some(Duration::from_secs(31)), min_interval, false), (Some(pending(200)), None));
+        //Something new wanted wins over what was waiting
+        assert_eq!(coalesce_write(Some(pending(300)), waiting, Some(Duration::from_secs(31)), min_interval, false), (Some(pending(300)), None));
+    }
+
+    #[test]
+    fn failed_writes_are_retried_next_poll() {
+        let min_interval = Duration::from_secs(30);
+        assert_eq!(retry_failed_write(Some(pending(100)), false), None);
+        assert_eq!(retry_failed_write(None, true), None);
+
+        let (write, waiting) = coalesce_write(Some(pending(100)), None, None, min_interval, false);
+        assert_eq!(waiting, None);
+        let waiting = retry_failed_write(write, true);
+        assert_eq!(waiting, Some(pending(100)));
+
+        //The next poll wants nothing new, so the failed write goes out again once the interval is up
+        assert_eq!(coalesce_write(None, waiting.clone(), Some(Duration::from_secs(5)), min_interval, false), (None, Some(pending(100))));
+        let (write, waiting) = coalesce_write(None, waiting, Some(Duration::from_secs(31)), min_interval, false);
+        assert_eq!((write.clone(), waiting), (Some(pending(100)), None));
+        assert_eq!(retry_failed_write(write, false), None);
+
+        //Newer limits replace a failed write rather than both going out
+        let waiting = retry_failed_write(Some(pending(100)), true);
+        assert_eq!(coalesce_write(Some(pending(200)), waiting, Some(Duration::from_secs(31)), min_interval, false), (Some(pending(200)), None));
+    }
+
+    #[test]
+    fn bypassing_the_interval_flushes_waiting_limits() {
+        let min_interval = Duration::from_secs(30);
+        assert_eq!(coalesce_write(None, Some(pending(100)), Some(Duration::from_secs(5)), min_interval, true), (Some(pending(100)), None));
+        assert_eq!(coalesce_write(Some(pending(200)), Some(pending(100)), Some(Duration::from_secs(5)), min_interval, true), (Some(pending(200)), None));
+    }
+
+    fn set_cookies(cookies: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for cookie in cookies {
+            headers.append(SET_COOKIE, HeaderValue::from_str(cookie).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn finds_the_session_cookie() {
+        assert_eq!(session_cookie(&set_cookies(&["SID=abc123; HttpOnly; path=/"])), Some("SID=abc123".to_string()));
+        assert_eq!(session_cookie(&set_cookies(&["Sid=abc123; HttpOnly"])), Some("Sid=abc123".to_string()));
+        assert_eq!(session_cookie(&set_cookies(&["QBT_SID_8080=abc123; path=/"])), Some("QBT_SID_8080=abc123".to_string()));
+        assert_eq!(session_cookie(&set_cookies(&[])), None);
+        assert_eq!(session_cookie(&set_cookies(&["HttpOnly"])), None);
+    }
+
+    #[test]
+    fn picks_the_session_cookie_out_of_several() {
+        let headers = set_cookies(&["lang=en; path=/", "SID=abc123; HttpOnly", "theme=dark"]);
+        assert_eq!(session_cookie(&headers), Some("SID=abc123".to_string()));
+        //Other cookies aren't a session, sending one back would only fail later
+        let headers = set_cookies(&["proxy_session=xyz; path=/", "lang=en"]);
+        assert_eq!(session_cookie(&headers), None);
+    }
+
+    #[test]
+    fn redacts_secrets_long_enough_to_be_unambiguous() {
+        let secrets = ["0123456789abcdef", "pw", ""];
+        assert_eq!(redact_secrets("Token=0123456789abcdef&user=pw", &secrets), "Token=***&user=pw");
+        assert_eq!(redact_secrets(r#"{"up_limit":1000,"password":"pw"}"#, &secrets), r#"{"up_limit":1000,"password":"pw"}"#);
+        assert_eq!(redact_secrets("nothing secret", &[]), "nothing secret");
+    }
+
+    #[test]
+    fn redacts_cookie_values() {
+        assert_eq!(redact_cookie_value("Cookie: SID=abc123; lang=en", "SID="), "Cookie: SID=***; lang=en");
+        assert_eq!(redact_cookie_value("cookie: sid=abc123", "SID="), "cookie: sid=***");
+        assert_eq!(redact_cookie_value("SID=abc, Sid=def", "SID="), "SID=***, Sid=***");
+        assert_eq!(redact_cookie_value("{\"cookie\":\"SID=abc123\"}", "SID="), "{\"cookie\":\"SID=***\"}");
+        assert_eq!(redact_cookie_value("QBT_SID_8080=abc123", "QBT_SID_"), "QBT_SID_***");
+        assert_eq!(redact_cookie_value("no cookies here", "SID="), "no cookies here");
+    }
+
+    #[test]
+    fn negative_limits_are_unlimited() {
+        assert_eq!(normalize_limit(-1), 0);
+        assert_eq!(normalize_limit(i64::MIN), 0);
+        assert_eq!(normalize_limit(0), 0);
+        assert_eq!(normalize_limit(1000), 1000);
+    }
+
+    #[test]
+    fn deserializes_negative_transfer_limits() {
+        let info: QbTransferInfo = serde_json::from_str(r#"{"up_rate_limit":-1,"dl_rate_limit":0}"#).unwrap();
+        assert_eq!((info.up_rate_limit, info.dl_rate_limit), (Some(0), Some(0)));
+        let info: QbTransferInfo = serde_json::from_str(r#"{"up_rate_limit":1000,"dl_rate_limit":-1}"#).unwrap();
+        assert_eq!((info.up_rate_limit, info.dl_rate_limit), (Some(1000), Some(0)));
+        let info: QbTransferInfo = serde_json::from_str(r#"{"up_rate_limit":null}"#).unwrap();
+        assert_eq!((info.up_rate_limit, info.dl_rate_limit), (None, None));
+    }
+
+    #[test]
+    fn builds_the_preferences_payload() {
+        let upload_only = TransferLimits { upload: 1000, download: None, scheduler_enabled: None };
+        assert_eq!(preferences_json(limit_preferences(upload_only)), r#"{"up_limit":1000}"#);
+
+        let both = TransferLimits { upload: 1000, download: Some(5000), scheduler_enabled: None };
+        assert_eq!(preferences_json(limit_preferences(both)), r#"{"dl_limit":5000,"up_limit":1000}"#);
+
+        let unlimited = TransferLimits { upload: 0, download: Some(0), scheduler_enabled: Some(true) };
+        assert_eq!(preferences_json(limit_preferences(unlimited)), r#"{"dl_limit":0,"scheduler_enabled":true,"up_limit":0}"#);
+    }
+
+    #[test]
+    fn u32_limits_over_the_max_are_fatal() {
+        let env_config = HashMap::from([
+            ("FITS".to_string(), Some(u32::MAX.to_string())),
+            ("WRAPS".to_string(), Some("4294967296".to_string())),
+        ]);
+        let mut problems = ConfigProblems::default();
+        assert_eq!(parse_env_u32(&env_config, &mut problems, "FITS", 7), u32::MAX);
+        assert!(problems.fatal.is_empty());
+        assert_eq!(parse_env_u32(&env_config, &mut problems, "WRAPS", 7), 7);
+        assert_eq!(problems.fatal.len(), 1);
+    }
+}
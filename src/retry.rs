@@ -0,0 +1,146 @@
+use std::str::FromStr;
+use std::time::Duration;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use tracing::warn;
+use crate::{During, Operation, ThrottlerError};
+
+//No retry waits longer than this however the delay grows, a request still failing by then is better returned
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+//Which responses and request failures are worth another go. Anything else is returned straight away,
+//4xx responses in particular since sending the same request again won't change the answer
+#[derive(Clone, Debug, PartialEq)]
+pub enum RetryOn {
+    //A single status code like 429
+    Status(StatusCode),
+    //A class of status codes, 5 for 5xx
+    StatusClass(u16),
+    //The connection couldn't be made
+    Connect,
+    //The request or connection timed out
+    Timeout,
+}
+
+impl FromStr for RetryOn {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim().to_lowercase();
+        match value.as_str() {
+            "connect" => return Ok(RetryOn::Connect),
+            "timeout" => return Ok(RetryOn::Timeout),
+            _ => {}
+        }
+        if let Some(class) = value.strip_suffix("xx").and_then(|class| class.parse::<u16>().ok()).filter(|class| (1..=5).contains(class)) {
+            return Ok(RetryOn::StatusClass(class));
+        }
+        value.parse::<u16>().ok()
+            .and_then(|status| StatusCode::from_u16(status).ok())
+            .map(RetryOn::Status)
+            .ok_or_else(|| format!("unknown retry condition {value}, expected a status code, a class like 5xx, connect or timeout"))
+    }
+}
+
+//Applied to every request to qBittorrent and the session source. Retries wait base_delay, then
+//multiplier times longer for each one after
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    //Retries after the first attempt, 0 never retries
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub multiplier: f64,
+    pub retry_on: Vec<RetryOn>,
+}
+
+impl RetryPolicy {
+    fn retries_status(&self, status: StatusCode) -> bool {
+        self.retry_on.iter().any(|retry_on| match retry_on {
+            RetryOn::Status(retry_status) => *retry_status == status,
+            RetryOn::StatusClass(class) => status.as_u16() / 100 == *class,
+            _ => false
+        })
+    }
+
+    fn retries_error(&self, err: &reqwest::Error) -> bool {
+        self.retry_on.iter().any(|retry_on| match retry_on {
+            RetryOn::Connect => err.is_connect(),
+            RetryOn::Timeout => err.is_timeout(),
+            _ => false
+        })
+    }
+
+    fn delay(&self, retry: u32) -> Duration {
+        //Anything past the cap, including an overflow, just waits the cap
+        let delay = self.base_delay.as_secs_f64() * self.multiplier.powi(i32::try_from(retry.saturating_sub(1)).unwrap_or(i32::MAX));
+        Duration::try_from_secs_f64(delay).unwrap_or(MAX_RETRY_DELAY).min(MAX_RETRY_DELAY)
+    }
+
+    //Sends the request, retrying as configured. The last response is returned whatever its status,
+    //so callers still decide what counts as success
+    pub async fn send(&self, request: RequestBuilder, operation: Operation) -> Result<Response, ThrottlerError> {
+        let mut retry = 0;
+        loop {
+            //Requests with a streaming body can't be cloned, those only get the one attempt
+            let Some(attempt) = request.try_clone().filter(|_| retry < self.max_retries) else {
+                return request.send().await.during(operation);
+            };
+
+            let reason = match attempt.send().await {
+                Ok(response) if self.retries_status(response.status()) => format!("returned {}", response.status()),
+                Err(err) if self.retries_error(&err) => err.without_url().to_string(),
+                result => return result.during(operation),
+            };
+            retry += 1;
+            let delay = self.delay(retry);
+            warn!("{operation} request {reason}, retrying in {}ms ({retry}/{})", delay.as_millis(), self.max_retries);
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retry_policy(base_delay: Duration, multiplier: f64, retry_on: Vec<RetryOn>) -> RetryPolicy {
+        RetryPolicy { max_retries: 3, base_delay, multiplier, retry_on }
+    }
+
+    #[test]
+    fn parses_retry_conditions() {
+        assert_eq!("5xx".parse(), Ok(RetryOn::StatusClass(5)));
+        assert_eq!(" 5XX ".parse(), Ok(RetryOn::StatusClass(5)));
+        assert_eq!("429".parse(), Ok(RetryOn::Status(StatusCode::TOO_MANY_REQUESTS)));
+        assert_eq!("connect".parse(), Ok(RetryOn::Connect));
+        assert_eq!("Timeout".parse(), Ok(RetryOn::Timeout));
+        assert!("junk".parse::<RetryOn>().is_err());
+        assert!("6xx".parse::<RetryOn>().is_err());
+        assert!("99".parse::<RetryOn>().is_err());
+        assert!("".parse::<RetryOn>().is_err());
+    }
+
+    #[test]
+    fn retries_listed_statuses_only() {
+        let policy = retry_policy(Duration::from_millis(500), 2.0, vec![RetryOn::StatusClass(5), RetryOn::Status(StatusCode::TOO_MANY_REQUESTS), RetryOn::Connect]);
+        assert!(policy.retries_status(StatusCode::INTERNAL_SERVER_ERROR));
+        assert!(policy.retries_status(StatusCode::BAD_GATEWAY));
+        assert!(policy.retries_status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!policy.retries_status(StatusCode::FORBIDDEN));
+        assert!(!policy.retries_status(StatusCode::OK));
+        assert!(!retry_policy(Duration::ZERO, 1.0, Vec::new()).retries_status(StatusCode::INTERNAL_SERVER_ERROR));
+    }
+
+    #[test]
+    fn delay_grows_by_the_multiplier_up_to_the_cap() {
+        let policy = retry_policy(Duration::from_millis(500), 2.0, Vec::new());
+        assert_eq!(policy.delay(1), Duration::from_millis(500));
+        assert_eq!(policy.delay(2), Duration::from_secs(1));
+        assert_eq!(policy.delay(3), Duration::from_secs(2));
+        assert_eq!(policy.delay(100), MAX_RETRY_DELAY);
+        assert_eq!(policy.delay(u32::MAX), MAX_RETRY_DELAY);
+
+        assert_eq!(retry_policy(Duration::from_secs(3600), 1.0, Vec::new()).delay(1), MAX_RETRY_DELAY);
+        assert_eq!(retry_policy(Duration::from_millis(500), f64::INFINITY, Vec::new()).delay(2), MAX_RETRY_DELAY);
+        assert_eq!(retry_policy(Duration::from_millis(500), f64::MAX, Vec::new()).delay(3), MAX_RETRY_DELAY);
+    }
+}
@@ -0,0 +1,41 @@
+use std::io;
+use std::time::Duration;
+use tokio::net::{lookup_host, UdpSocket};
+use tracing::debug;
+
+//Every metric name starts with this
+const PREFIX: &str = "qbthrottler";
+
+//Pushes metrics to a StatsD server over UDP. Sends never wait, a full socket buffer or an unreachable server
+//just loses that metric rather than holding up the poll
+pub struct Statsd {
+    socket: UdpSocket,
+}
+
+impl Statsd {
+    pub async fn connect(address: &str) -> io::Result<Self> {
+        let target = lookup_host(address).await?.next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{address} didn't resolve to anything")))?;
+        let socket = UdpSocket::bind(if target.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" }).await?;
+        socket.connect(target).await?;
+        Ok(Statsd { socket })
+    }
+
+    pub fn gauge(&self, name: &str, value: u64) {
+        self.send(&format!("{PREFIX}.{name}:{value}|g"));
+    }
+
+    pub fn count(&self, name: &str, value: u64) {
+        self.send(&format!("{PREFIX}.{name}:{value}|c"));
+    }
+
+    pub fn timing(&self, name: &str, duration: Duration) {
+        self.send(&format!("{PREFIX}.{name}:{}|ms", duration.as_millis()));
+    }
+
+    fn send(&self, metric: &str) {
+        if let Err(err) = self.socket.try_send(metric.as_bytes()) {
+            debug!("Failed to send {metric} to StatsD: {err}");
+        }
+    }
+}
@@ -0,0 +1,286 @@
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error};
+use crate::{QbTransferInfo, TransferLimits};
+
+//Only the most recent transitions are kept for the status page
+const MAX_TRANSITIONS: usize = 20;
+const MAX_REQUEST_BYTES: usize = 8192;
+
+pub type SharedState = Arc<Mutex<RuntimeState>>;
+
+#[derive(Clone, Debug)]
+pub struct Transition {
+    pub at: Instant,
+    pub throttled: bool,
+    pub sessions: usize,
+    pub reason: String,
+}
+
+//What the poll loop last saw and did, shared with the status server
+#[derive(Clone, Debug)]
+pub struct RuntimeState {
+    pub started_at: Instant,
+    pub throttled: bool,
+    pub active_sessions: Option<usize>,
+    pub applied_limits: Option<TransferLimits>,
+    //Why applied_limits were picked
+    pub reason: Option<String>,
+    pub last_poll: Option<Instant>,
+    //The last poll that got the sessions and, if it had to, wrote the limits
+    pub last_successful_poll: Option<Instant>,
+    pub transitions: VecDeque<Transition>,
+    //Totals over the whole run, unlike transitions which only keeps the latest
+    pub transition_count: u64,
+    pub throttled_total: Duration,
+    pub throttled_since: Option<Instant>,
+    //None when the status page couldn't read it on the last poll
+    pub transfer_info: Option<QbTransferInfo>,
+    //The latest polled responses with secrets redacted, only kept for /debug/last
+    pub last_sessions_response: Option<String>,
+    pub last_transfer_info_response: Option<String>,
+}
+
+impl RuntimeState {
+    pub fn new() -> Self {
+        RuntimeState {
+            started_at: Instant::now(),
+            throttled: false,
+            active_sessions: None,
+            applied_limits: None,
+            reason: None,
+            last_poll: None,
+            last_successful_poll: None,
+            transitions: VecDeque::new(),
+            transition_count: 0,
+            throttled_total: Duration::ZERO,
+            throttled_since: None,
+            transfer_info: None,
+            last_sessions_response: None,
+            last_transfer_info_response: None,
+        }
+    }
+
+    pub fn record_poll(&mut self, sessions: Option<usize>, succeeded: bool) {
+        self.last_poll = Some(Instant::now());
+        if succeeded {
+            self.last_successful_poll = self.last_poll;
+        }
+        if sessions.is_some() {
+            self.active_sessions = sessions;
+        }
+    }
+
+    //Returns whether this was a transition
+    pub fn set_throttled(&mut self, throttled: bool, sessions: usize, reason: &str) -> bool {
+        if self.throttled == throttled {
+            return false;
+        }
+
+        self.throttled = throttled;
+        self.transitions.push_front(Transition { at: Instant::now(), throttled, sessions, reason: reason.to_string() });
+        self.transitions.truncate(MAX_TRANSITIONS);
+        self.transition_count += 1;
+        if throttled {
+            self.throttled_since = Some(Instant::now());
+        } else if let Some(since) = self.throttled_since.take() {
+            self.throttled_total += since.elapsed();
+        }
+        true
+    }
+
+    //Total time spent throttled, including the current throttle if there is one
+    pub fn throttled_time(&self) -> Duration {
+        self.throttled_total + self.throttled_since.map_or(Duration::ZERO, |since| since.elapsed())
+    }
+}
+
+//healthz treats the loop as stuck or failing once it's gone this many poll intervals without a successful poll
+const HEALTHY_POLL_INTERVALS: u32 = 2;
+
+//debug_endpoint turns on /debug/last
+pub async fn serve(listener: TcpListener, state: SharedState, poll_interval: Duration, debug_endpoint: bool) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                debug!("Status request from {peer}");
+                tokio::spawn(handle_connection(stream, state.clone(), poll_interval, debug_endpoint));
+            }
+            Err(err) => {
+                error!("Failed to accept status connection: {err}");
+            }
+        }
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, state: SharedState, poll_interval: Duration, debug_endpoint: bool) {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    //Only the request line matters, so read until the end of the headers and ignore any body
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < MAX_REQUEST_BYTES {
+        match stream.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(read) => request.extend_from_slice(&buf[..read]),
+            Err(err) => {
+                debug!("Failed to read status request: {err}");
+                return;
+            }
+        }
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+
+    let snapshot = state.lock().unwrap().clone();
+    let (status, content_type, body) = match (method, path) {
+        ("GET", "/") => ("200 OK", "text/html; charset=utf-8", render_status_page(&snapshot)),
+        ("GET", "/healthz") => render_health(&snapshot, poll_interval),
+        ("GET", "/debug/last") if debug_endpoint => ("200 OK", "application/json", render_last_responses(&snapshot)),
+        _ => ("404 Not Found", "text/plain; charset=utf-8", "Not Found".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    if let Err(err) = stream.write_all(response.as_bytes()).await {
+        debug!("Failed to write status response: {err}");
+    }
+}
+
+//For container health checks, only healthy while polls are still getting the sessions and writing the limits.
+//A loop that keeps polling but fails every time is unhealthy too
+fn render_health(state: &RuntimeState, poll_interval: Duration) -> (&'static str, &'static str, String) {
+    match state.last_successful_poll.map(|at| at.elapsed()) {
+        Some(age) if age <= poll_interval * HEALTHY_POLL_INTERVALS => ("200 OK", "text/plain; charset=utf-8", format!("ok, last successful poll {}ms ago", age.as_millis())),
+        Some(age) => ("503 Service Unavailable", "text/plain; charset=utf-8", format!("stale, last successful poll {}ms ago", age.as_millis())),
+        None => ("503 Service Unavailable", "text/plain; charset=utf-8", "no successful poll yet".to_string()),
+    }
+}
+
+//The responses are already JSON, so they're put in as they are. Sessions pushed over the WebSocket aren't
+//captured, so with it in use the sessions are from the last time Jellyfin was polled
+fn render_last_responses(state: &RuntimeState) -> String {
+    format!(
+        "{{\"sessions\": {}, \"qbittorrent_transfer_info\": {}}}",
+        state.last_sessions_response.as_deref().unwrap_or("null"),
+        state.last_transfer_info_response.as_deref().unwrap_or("null")
+    )
+}
+
+fn render_status_page(state: &RuntimeState) -> String {
+    let active_sessions = state.active_sessions.map_or("unknown".to_string(), |sessions| sessions.to_string());
+    let applied_limits = state.applied_limits.map_or("none yet".to_string(), |limits| limits.to_string());
+    let reason = state.reason.as_deref().map_or("none yet".to_string(), escape_html);
+    let transfer_info = state.transfer_info.clone().unwrap_or_default();
+    let speed = |speed: Option<u64>| speed.map_or("unknown".to_string(), |speed| format!("{speed} B/s"));
+    //qBittorrent reports no limit as 0
+    let limit = |limit: Option<u64>| match limit {
+        Some(0) => "unlimited".to_string(),
+        limit => speed(limit)
+    };
+    let last_poll = state.last_poll.map_or("never".to_string(), |at| format!("{} seconds ago", at.elapsed().as_secs()));
+    let last_successful_poll = state.last_successful_poll.map_or("never".to_string(), |at| format!("{} seconds ago", at.elapsed().as_secs()));
+
+    let mut transitions = String::new();
+    for transition in &state.transitions {
+        let _ = write!(
+            transitions,
+            "<tr><td>{} seconds ago</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            transition.at.elapsed().as_secs(),
+            if transition.throttled { "throttled" } else { "unthrottled" },
+            transition.sessions,
+            escape_html(&transition.reason)
+        );
+    }
+
+    format!(
+        "<!DOCTYPE html>
+<html>
+<head><title>qBitThrottler</title></head>
+<body>
+<h1>qBitThrottler</h1>
+<table>
+<tr><th>State</th><td>{}</td></tr>
+<tr><th>Active sessions</th><td>{active_sessions}</td></tr>
+<tr><th>Applied limit</th><td>{applied_limits}</td></tr>
+<tr><th>Reason</th><td>{reason}</td></tr>
+<tr><th>Last poll</th><td>{last_poll}</td></tr>
+<tr><th>Last successful poll</th><td>{last_successful_poll}</td></tr>
+<tr><th>Uptime</th><td>{} seconds</td></tr>
+</table>
+<h2>qBittorrent</h2>
+<table>
+<tr><th>Connection</th><td>{}</td></tr>
+<tr><th>Upload speed</th><td>{}</td></tr>
+<tr><th>Download speed</th><td>{}</td></tr>
+<tr><th>Upload limit</th><td>{}</td></tr>
+<tr><th>Download limit</th><td>{}</td></tr>
+</table>
+<h2>Recent transitions</h2>
+<table>
+<tr><th>When</th><th>State</th><th>Sessions</th><th>Reason</th></tr>
+{transitions}
+</table>
+</body>
+</html>
+",
+        if state.throttled { "throttled" } else { "unthrottled" },
+        state.started_at.elapsed().as_secs(),
+        transfer_info.connection_status.as_deref().unwrap_or("unknown"),
+        speed(transfer_info.up_info_speed),
+        speed(transfer_info.dl_info_speed),
+        limit(transfer_info.up_rate_limit),
+        limit(transfer_info.dl_rate_limit)
+    )
+}
+
+//One line of JSON for SIGUSR1, for a quick look without the status page
+pub fn render_status_line(state: &RuntimeState) -> String {
+    serde_json::json!({
+        "throttled": state.throttled,
+        "active_sessions": state.active_sessions,
+        "upload_limit": state.applied_limits.map(|limits| limits.upload),
+        "download_limit": state.applied_limits.and_then(|limits| limits.download),
+        "reason": state.reason,
+        "last_poll_secs_ago": state.last_poll.map(|at| at.elapsed().as_secs()),
+        "last_successful_poll_secs_ago": state.last_successful_poll.map(|at| at.elapsed().as_secs()),
+        "uptime_secs": state.started_at.elapsed().as_secs(),
+    }).to_string()
+}
+
+//Reasons can quote the pause file path, which could contain anything
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn health_follows_the_last_successful_poll() {
+        let poll_interval = Duration::from_secs(5);
+        let mut state = RuntimeState::new();
+        assert_eq!(render_health(&state, poll_interval).0, "503 Service Unavailable");
+
+        //Polling without succeeding doesn't count
+        state.record_poll(None, false);
+        assert_eq!(render_health(&state, poll_interval).0, "503 Service Unavailable");
+
+        state.record_poll(Some(1), true);
+        assert_eq!(render_health(&state, poll_interval).0, "200 OK");
+
+        state.last_successful_poll = Some(Instant::now() - poll_interval * (HEALTHY_POLL_INTERVALS + 1));
+        state.record_poll(Some(1), false);
+        assert_eq!(render_health(&state, poll_interval).0, "503 Service Unavailable");
+        //A longer interval, like the warmup one, allows longer between successes
+        assert_eq!(render_health(&state, poll_interval * 2).0, "200 OK");
+    }
+}
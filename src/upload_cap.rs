@@ -0,0 +1,128 @@
+use std::fs;
+use chrono::{Datelike, Local, NaiveDate, TimeZone};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+//What's been uploaded in the current month, saved so a restart doesn't start the count again
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct UploadTotal {
+    //As YYYY-MM in local time
+    month: String,
+    uploaded: u64,
+}
+
+//Tracks upload against a monthly cap from qBittorrent's session upload total. Once enough of the cap is used, upload is
+//held to the rate that would use up what's left exactly at the end of the month
+pub struct UploadCap {
+    cap: u64,
+    start_percent: f64,
+    //Empty keeps the total in memory only
+    state_file: String,
+    total: UploadTotal,
+    //qBittorrent's up_info_data at the last poll, None until the first one
+    last_session_total: Option<u64>,
+    save_failed: bool,
+}
+
+impl UploadCap {
+    pub fn load(cap: u64, start_percent: f64, state_file: &str) -> Self {
+        let total = match state_file {
+            "" => None,
+            path => match fs::read_to_string(path) {
+                Ok(contents) => match serde_json::from_str::<UploadTotal>(&contents) {
+                    Ok(total) => Some(total),
+                    Err(err) => {
+                        warn!("Ignoring upload total in {path}, it isn't valid: {err}");
+                        None
+                    }
+                },
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+                Err(err) => {
+                    warn!("Failed to read upload total from {path}: {err}");
+                    None
+                }
+            }
+        };
+        let month = current_month();
+        let total = match total {
+            Some(total) if total.month == month => {
+                info!("Resuming with {} bytes uploaded so far in {month}", total.uploaded);
+                total
+            }
+            _ => UploadTotal { month, uploaded: 0 },
+        };
+
+        UploadCap { cap, start_percent, state_file: state_file.to_string(), total, last_session_total: None, save_failed: false }
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.total.uploaded
+    }
+
+    //session_total only ever grows while qBittorrent is running, so going backwards means it restarted and
+    //everything it reports was uploaded since
+    pub fn record(&mut self, session_total: u64) {
+        let uploaded = match self.last_session_total.replace(session_total) {
+            None => 0,
+            Some(last) if session_total < last => session_total,
+            Some(last) => session_total - last,
+        };
+
+        let month = current_month();
+        if month != self.total.month {
+            info!("Uploaded {} bytes in {}, starting the count for {month}", self.total.uploaded, self.total.month);
+            self.total = UploadTotal { month, uploaded: 0 };
+        } else if uploaded == 0 {
+            return;
+        }
+        self.total.uploaded = self.total.uploaded.saturating_add(uploaded);
+        self.save();
+    }
+
+    //0 is unlimited to qBittorrent, so any budget is lower than it. The budget never drops below 1 since 0 would lift the limit
+    pub fn limit(&self, upload: u32) -> u32 {
+        if (self.total.uploaded as f64) < self.cap as f64 * self.start_percent / 100.0 {
+            return upload;
+        }
+
+        let remaining = self.cap.saturating_sub(self.total.uploaded);
+        let budget = (remaining / seconds_left_in_month().max(1)).clamp(1, u32::MAX as u64) as u32;
+        if upload == 0 || budget < upload {
+            debug!("{} of {} bytes uploaded this month, holding upload to {budget}", self.total.uploaded, self.cap);
+            budget
+        } else {
+            upload
+        }
+    }
+
+    fn save(&mut self) {
+        if self.state_file.is_empty() {
+            return;
+        }
+
+        //The total is a couple of numbers, so it's written every time rather than batched
+        let result = serde_json::to_string(&self.total).map_err(|err| err.to_string())
+            .and_then(|contents| fs::write(&self.state_file, contents).map_err(|err| err.to_string()));
+        match result {
+            Ok(()) => self.save_failed = false,
+            Err(err) if !self.save_failed => {
+                warn!("Failed to save the upload total to {}, it will be lost on restart: {err}", self.state_file);
+                self.save_failed = true;
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+fn current_month() -> String {
+    Local::now().format("%Y-%m").to_string()
+}
+
+fn seconds_left_in_month() -> u64 {
+    let now = Local::now();
+    let (year, month) = if now.month() == 12 { (now.year() + 1, 1) } else { (now.year(), now.month() + 1) };
+    NaiveDate::from_ymd_opt(year, month, 1)
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .and_then(|midnight| Local.from_local_datetime(&midnight).earliest())
+        .map_or(1, |next_month| (next_month - now).num_seconds().max(1) as u64)
+}